@@ -0,0 +1,36 @@
+# memo bash completion
+# add `eval "$(memo completion bash)"` to .bashrc
+# files are offered when nothing else matches, e.g. for import and merge
+
+_memo() {
+  local cur=${COMP_WORDS[COMP_CWORD]} sub="" i
+  for ((i = 1; i < COMP_CWORD; i++)); do
+    case ${COMP_WORDS[i]} in
+      --local | --read-only | --json) ;;
+      *) sub=${COMP_WORDS[i]}; break ;;
+    esac
+  done
+
+  case $sub in
+    "")
+      COMPREPLY=($(compgen -W "list save print run pick stats top doctor tag edit seq run-seq
+        prune delete clear touch pin unpin resolve import merge completion
+        --local --read-only --json --help" -- "$cur"))
+      [[ $cur == -* ]] && return
+      # saved commands starting with what was typed, quoted as one word
+      local cmd
+      while IFS= read -r cmd; do
+        COMPREPLY+=("$(printf %q "$cmd")")
+      done < <(command memo _complete "$cur" 2>/dev/null)
+      ;;
+    run | print | edit | delete | touch | pin | unpin | tag)
+      [[ $cur == -* ]] && return
+      COMPREPLY=($(compgen -W "$(command memo _list 2>/dev/null | cut -f1)" -- "$cur"))
+      ;;
+    completion)
+      COMPREPLY=($(compgen -W "bash zsh fish" -- "$cur"))
+      ;;
+  esac
+}
+
+complete -F _memo -o default memo
@@ -0,0 +1,47 @@
+# memo fish completion
+# run `memo completion fish > ~/.config/fish/completions/memo.fish`
+
+# memo _list prints index<TAB>command; fish shows what follows the tab as the
+# description.
+function __memo_entries
+    command memo _list 2>/dev/null
+end
+
+set -l subcommands list save print run pick stats top doctor tag edit seq run-seq prune delete \
+    clear touch pin unpin resolve import merge completion
+set -l none "not __fish_seen_subcommand_from $subcommands"
+
+complete -c memo -f
+complete -c memo -n $none -l local -d 'use the nearest .memo.sqlite3'
+complete -c memo -n $none -l read-only -d 'never modify the database'
+complete -c memo -n $none -l json -d 'print listings as JSON'
+complete -c memo -n $none -s h -l help -d 'show usage'
+complete -c memo -n $none -a list -d 'list saved commands'
+complete -c memo -n $none -a save -d 'save the last or an explicit command'
+complete -c memo -n $none -a print -d 'print a command'
+complete -c memo -n $none -a run -d 'execute a command'
+complete -c memo -n $none -a pick -d 'choose several commands'
+complete -c memo -n $none -a stats -d 'count saved commands per day or hour'
+complete -c memo -n $none -a top -d 'list the most copied and run commands'
+complete -c memo -n $none -a doctor -d 'list commands whose program is gone'
+complete -c memo -n $none -a tag -d 'add or remove tags'
+complete -c memo -n $none -a edit -d 'change a command in your editor'
+complete -c memo -n $none -a seq -d 'manage named sequences'
+complete -c memo -n $none -a run-seq -d 'run a named sequence'
+complete -c memo -n $none -a prune -d 'delete adjacent duplicates'
+complete -c memo -n $none -a delete -d 'delete commands'
+complete -c memo -n $none -a clear -d 'delete every command'
+complete -c memo -n $none -a touch -d 'move a command to the top'
+complete -c memo -n $none -a pin -d 'keep a command when old entries are evicted'
+complete -c memo -n $none -a unpin -d 'let a command be evicted again'
+complete -c memo -n $none -a resolve -d 'print the command a reference refers to'
+complete -c memo -n $none -a import -d 'add commands from a shell history file'
+complete -c memo -n $none -a merge -d 'add commands from another memo database'
+complete -c memo -n $none -a completion -d 'print a shell completion script'
+complete -c memo -n $none -k -a '(command memo _complete (commandline -ct) 2>/dev/null)' \
+    -d 'saved command'
+
+complete -c memo -n '__fish_seen_subcommand_from run print edit delete touch pin unpin tag' \
+    -k -a '(__memo_entries)'
+complete -c memo -n '__fish_seen_subcommand_from import merge' -F
+complete -c memo -n '__fish_seen_subcommand_from completion' -a 'bash zsh fish'
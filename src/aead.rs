@@ -0,0 +1,233 @@
+//! ChaCha20-Poly1305 (RFC 8439), used to encrypt saved commands at rest.
+//! Only sealing and opening whole messages without associated data is
+//! needed, so that is all this implements.
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u8; KEY_LEN], counter: u32, nonce: &[u8; NONCE_LEN]) -> [u8; 64] {
+    let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for i in 0..8 {
+        state[4 + i] = word(&key[i * 4..]);
+    }
+    state[12] = counter;
+    for i in 0..3 {
+        state[13 + i] = word(&nonce[i * 4..]);
+    }
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+    let mut out = [0u8; 64];
+    for i in 0..16 {
+        let sum = working[i].wrapping_add(state[i]);
+        out[i * 4..i * 4 + 4].copy_from_slice(&sum.to_le_bytes());
+    }
+    out
+}
+
+/// XORs `data` with the keystream starting at block `counter`.
+fn chacha20_xor(key: &[u8; KEY_LEN], counter: u32, nonce: &[u8; NONCE_LEN], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (byte, k) in chunk.iter_mut().zip(block) {
+            *byte ^= k;
+        }
+    }
+}
+
+/// Poly1305 over `msg` with a one-time key, in 26-bit limbs.
+fn poly1305(key: &[u8; 32], msg: &[u8]) -> [u8; TAG_LEN] {
+    let word = |i: usize| u32::from_le_bytes([key[i], key[i + 1], key[i + 2], key[i + 3]]);
+    let r0 = word(0) & 0x03ff_ffff;
+    let r1 = (word(3) >> 2) & 0x03ff_ff03;
+    let r2 = (word(6) >> 4) & 0x03ff_c0ff;
+    let r3 = (word(9) >> 6) & 0x03f0_3fff;
+    let r4 = (word(12) >> 8) & 0x000f_ffff;
+    let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+    let mut h = [0u32; 5];
+
+    for chunk in msg.chunks(16) {
+        let mut block = [0u8; 17];
+        block[..chunk.len()].copy_from_slice(chunk);
+        block[chunk.len()] = 1;
+        let word = |i: usize| {
+            u32::from_le_bytes([block[i], block[i + 1], block[i + 2], block[i + 3]])
+        };
+        h[0] += word(0) & 0x03ff_ffff;
+        h[1] += (word(3) >> 2) & 0x03ff_ffff;
+        h[2] += (word(6) >> 4) & 0x03ff_ffff;
+        h[3] += (word(9) >> 6) & 0x03ff_ffff;
+        h[4] += (word(12) >> 8) | (u32::from(block[16]) << 24);
+
+        let m = |a: u32, b: u32| u64::from(a) * u64::from(b);
+        let d0 = m(h[0], r0) + m(h[1], s4) + m(h[2], s3) + m(h[3], s2) + m(h[4], s1);
+        let d1 = m(h[0], r1) + m(h[1], r0) + m(h[2], s4) + m(h[3], s3) + m(h[4], s2);
+        let d2 = m(h[0], r2) + m(h[1], r1) + m(h[2], r0) + m(h[3], s4) + m(h[4], s3);
+        let d3 = m(h[0], r3) + m(h[1], r2) + m(h[2], r1) + m(h[3], r0) + m(h[4], s4);
+        let d4 = m(h[0], r4) + m(h[1], r3) + m(h[2], r2) + m(h[3], r1) + m(h[4], r0);
+
+        let mut carry = d0 >> 26;
+        h[0] = d0 as u32 & 0x03ff_ffff;
+        let d1 = d1 + carry;
+        carry = d1 >> 26;
+        h[1] = d1 as u32 & 0x03ff_ffff;
+        let d2 = d2 + carry;
+        carry = d2 >> 26;
+        h[2] = d2 as u32 & 0x03ff_ffff;
+        let d3 = d3 + carry;
+        carry = d3 >> 26;
+        h[3] = d3 as u32 & 0x03ff_ffff;
+        let d4 = d4 + carry;
+        carry = d4 >> 26;
+        h[4] = d4 as u32 & 0x03ff_ffff;
+        h[0] += carry as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= 0x03ff_ffff;
+    }
+
+    // Fully carry h, then compute h - p and keep it if it didn't borrow.
+    let mut carry = h[1] >> 26;
+    h[1] &= 0x03ff_ffff;
+    for limb in &mut h[2..] {
+        *limb += carry;
+        carry = *limb >> 26;
+        *limb &= 0x03ff_ffff;
+    }
+    h[0] += carry * 5;
+    carry = h[0] >> 26;
+    h[0] &= 0x03ff_ffff;
+    h[1] += carry;
+
+    let mut g = [0u32; 5];
+    g[0] = h[0].wrapping_add(5);
+    carry = g[0] >> 26;
+    g[0] &= 0x03ff_ffff;
+    for (g, h) in g.iter_mut().zip(h).skip(1) {
+        *g = h.wrapping_add(carry);
+        carry = *g >> 26;
+        *g &= 0x03ff_ffff;
+    }
+    g[4] = g[4].wrapping_sub(1 << 26);
+    // All ones when g didn't borrow (h >= p), else zero.
+    let mask = (g[4] >> 31).wrapping_sub(1);
+    for (h, g) in h.iter_mut().zip(g) {
+        *h = (*h & !mask) | (g & mask);
+    }
+
+    let h0 = h[0] | (h[1] << 26);
+    let h1 = (h[1] >> 6) | (h[2] << 20);
+    let h2 = (h[2] >> 12) | (h[3] << 14);
+    let h3 = (h[3] >> 18) | (h[4] << 8);
+    let mut tag = [0u8; TAG_LEN];
+    let mut f: u64 = 0;
+    for (i, hw) in [h0, h1, h2, h3].into_iter().enumerate() {
+        f += u64::from(hw) + u64::from(word(16 + i * 4));
+        tag[i * 4..i * 4 + 4].copy_from_slice(&(f as u32).to_le_bytes());
+        f >>= 32;
+    }
+    tag
+}
+
+fn tag_for(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], ciphertext: &[u8]) -> [u8; TAG_LEN] {
+    let block = chacha20_block(key, 0, nonce);
+    let mut otk = [0u8; 32];
+    otk.copy_from_slice(&block[..32]);
+    let mut mac_data = ciphertext.to_vec();
+    mac_data.resize(ciphertext.len().div_ceil(16) * 16, 0);
+    mac_data.extend_from_slice(&0u64.to_le_bytes());
+    mac_data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly1305(&otk, &mac_data)
+}
+
+/// Encrypts `plaintext`, returning the ciphertext with the tag appended.
+pub fn seal(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], plaintext: &[u8]) -> Vec<u8> {
+    let mut out = plaintext.to_vec();
+    chacha20_xor(key, 1, nonce, &mut out);
+    let tag = tag_for(key, nonce, &out);
+    out.extend_from_slice(&tag);
+    out
+}
+
+/// Checks the tag and decrypts. None when the key or nonce is wrong or the
+/// data was changed.
+pub fn open(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], sealed: &[u8]) -> Option<Vec<u8>> {
+    let split = sealed.len().checked_sub(TAG_LEN)?;
+    let (ciphertext, tag) = sealed.split_at(split);
+    let expected = tag_for(key, nonce, ciphertext);
+    // Compare without an early exit.
+    let diff = expected.iter().zip(tag).fold(0, |acc, (a, b)| acc | (a ^ b));
+    if diff != 0 {
+        return None;
+    }
+    let mut out = ciphertext.to_vec();
+    chacha20_xor(key, 1, nonce, &mut out);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn poly1305_rfc8439_vector() {
+        let key = hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+        let tag = poly1305(&key.try_into().unwrap(), b"Cryptographic Forum Research Group");
+        assert_eq!(tag.to_vec(), hex("a8061dc1305136c6c22b8baf0c0127a9"));
+    }
+
+    #[test]
+    fn seal_matches_rfc8439_without_aad() {
+        // The RFC 8439 section 2.8.2 key, nonce and plaintext; the expected
+        // output differs from the RFC's because no associated data is used.
+        let key: [u8; KEY_LEN] = std::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; NONCE_LEN] = hex("070000004041424344454647").try_into().unwrap();
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only \
+                          one tip for the future, sunscreen would be it.";
+        let sealed = seal(&key, &nonce, plaintext);
+        assert_eq!(
+            sealed,
+            hex("d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d63dbea45e8ca9\
+                 671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b3692ddbd7f2d778b8c9803aee3\
+                 28091b58fab324e4fad675945585808b4831d7bc3ff4def08e4b7a9de576d26586cec64b6116\
+                 6a23a4681fd59456aea1d29f82477216")
+        );
+        assert_eq!(open(&key, &nonce, &sealed).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn open_rejects_wrong_key_and_tampering() {
+        let key = [7u8; KEY_LEN];
+        let nonce = [1u8; NONCE_LEN];
+        let mut sealed = seal(&key, &nonce, b"git push --force");
+        assert!(open(&[8u8; KEY_LEN], &nonce, &sealed).is_none());
+        sealed[0] ^= 1;
+        assert!(open(&key, &nonce, &sealed).is_none());
+        assert!(open(&key, &nonce, &[0u8; 4]).is_none());
+    }
+}
@@ -0,0 +1,365 @@
+//! Gzip decompression (RFC 1952 around RFC 1951 deflate), for reading
+//! rotated history files. The input is streamed once; only decoding is
+//! needed, so that is all this implements.
+
+use std::io::{self, BufRead, BufReader, Read};
+
+pub const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+const MAX_BITS: usize = 15;
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+    131, 163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] =
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are sent in.
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |c, &b| CRC_TABLE[((c ^ u32::from(b)) & 0xff) as usize] ^ (c >> 8))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("gzip: {msg}"))
+}
+
+/// Reads bits least significant first, a byte at a time.
+struct Bits<R> {
+    inner: R,
+    buf: u32,
+    count: u32,
+}
+
+impl<R: BufRead> Bits<R> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut b = [0u8];
+        self.inner.read_exact(&mut b).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("truncated data"),
+            _ => err,
+        })?;
+        Ok(b[0])
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            self.buf |= u32::from(self.byte()?) << self.count;
+            self.count += 8;
+        }
+        let value = self.buf & ((1 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Drops the rest of a partly read byte.
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+
+    fn u16_le(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes([self.byte()?, self.byte()?]))
+    }
+
+    fn u32_le(&mut self) -> io::Result<u32> {
+        Ok(u32::from(self.u16_le()?) | u32::from(self.u16_le()?) << 16)
+    }
+
+    /// Skips a zero-terminated header field.
+    fn skip_cstr(&mut self) -> io::Result<()> {
+        while self.byte()? != 0 {}
+        Ok(())
+    }
+}
+
+/// A canonical Huffman code: how many codes there are of each length, and
+/// the symbols in code order.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = left * 2 - i32::from(count);
+            if left < 0 {
+                return Err(invalid("over-subscribed code"));
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode<R: BufRead>(&self, bits: &mut Bits<R>) -> io::Result<u16> {
+        // Codes are sent most significant bit first, so they are built up a
+        // bit at a time and compared against each length's range.
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("bad code"))
+    }
+}
+
+fn fixed_codes() -> io::Result<(Huffman, Huffman)> {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes<R: BufRead>(bits: &mut Bits<R>) -> io::Result<(Huffman, Huffman)> {
+    let nlen = bits.bits(5)? as usize + 257;
+    let ndist = bits.bits(5)? as usize + 1;
+    let ncode = bits.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(invalid("bad code counts"));
+    }
+    let mut clens = [0u8; 19];
+    for &i in &CLEN_ORDER[..ncode] {
+        clens[i] = bits.bits(3)? as u8;
+    }
+    let clcode = Huffman::new(&clens)?;
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = clcode.decode(bits)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous =
+                    *lengths[..i].last().ok_or_else(|| invalid("repeat with no length"))?;
+                (previous, 3 + bits.bits(2)? as usize)
+            }
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        let end = i + repeat;
+        if end > lengths.len() {
+            return Err(invalid("too many lengths"));
+        }
+        lengths[i..end].fill(value);
+        i = end;
+    }
+    if lengths[256] == 0 {
+        return Err(invalid("no end-of-block code"));
+    }
+    Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..])?))
+}
+
+/// Decodes one block's symbols into `out` until its end-of-block code.
+fn inflate_codes<R: BufRead>(
+    bits: &mut Bits<R>,
+    out: &mut Vec<u8>,
+    start: usize,
+    lencode: &Huffman,
+    distcode: &Huffman,
+) -> io::Result<()> {
+    loop {
+        let symbol = lencode.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let sym = symbol - 257;
+                if sym >= LEN_BASE.len() {
+                    return Err(invalid("bad length code"));
+                }
+                let len = LEN_BASE[sym] as usize + bits.bits(LEN_EXTRA[sym].into())? as usize;
+                let sym = distcode.decode(bits)? as usize;
+                if sym >= DIST_BASE.len() {
+                    return Err(invalid("bad distance code"));
+                }
+                let dist = DIST_BASE[sym] as usize + bits.bits(DIST_EXTRA[sym].into())? as usize;
+                if dist > out.len() - start {
+                    return Err(invalid("distance too far back"));
+                }
+                // Byte by byte: the copy may overlap what it produces.
+                let from = out.len() - dist;
+                for k in 0..len {
+                    out.push(out[from + k]);
+                }
+            }
+        }
+    }
+}
+
+/// Inflates one deflate stream, appending to `out`.
+fn inflate<R: BufRead>(bits: &mut Bits<R>, out: &mut Vec<u8>) -> io::Result<()> {
+    let start = out.len();
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let len = bits.u16_le()?;
+                if bits.u16_le()? != !len {
+                    return Err(invalid("bad stored block length"));
+                }
+                let before = out.len();
+                out.resize(before + len as usize, 0);
+                bits.inner.read_exact(&mut out[before..]).map_err(|_| invalid("truncated data"))?;
+            }
+            1 => {
+                let (lencode, distcode) = fixed_codes()?;
+                inflate_codes(bits, out, start, &lencode, &distcode)?;
+            }
+            2 => {
+                let (lencode, distcode) = dynamic_codes(bits)?;
+                inflate_codes(bits, out, start, &lencode, &distcode)?;
+            }
+            _ => return Err(invalid("bad block type")),
+        }
+        if last {
+            bits.align();
+            return Ok(());
+        }
+    }
+}
+
+/// Decompresses gzip data, including several concatenated members, and
+/// checks each member's CRC and length.
+pub fn decompress(reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut bits = Bits { inner: BufReader::new(reader), buf: 0, count: 0 };
+    let mut out = Vec::new();
+    loop {
+        if bits.byte()? != MAGIC[0] || bits.byte()? != MAGIC[1] {
+            return Err(invalid("not gzip data"));
+        }
+        if bits.byte()? != 8 {
+            return Err(invalid("unknown compression method"));
+        }
+        let flags = bits.byte()?;
+        // Modification time, extra flags and OS.
+        for _ in 0..6 {
+            bits.byte()?;
+        }
+        if flags & FEXTRA != 0 {
+            let len = bits.u16_le()?;
+            for _ in 0..len {
+                bits.byte()?;
+            }
+        }
+        if flags & FNAME != 0 {
+            bits.skip_cstr()?;
+        }
+        if flags & FCOMMENT != 0 {
+            bits.skip_cstr()?;
+        }
+        if flags & FHCRC != 0 {
+            bits.u16_le()?;
+        }
+        let start = out.len();
+        inflate(&mut bits, &mut out)?;
+        let crc = bits.u32_le()?;
+        let size = bits.u32_le()?;
+        if crc != crc32(&out[start..]) || size != (out.len() - start) as u32 {
+            return Err(invalid("checksum mismatch"));
+        }
+        if bits.inner.fill_buf()?.is_empty() {
+            return Ok(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    // Vectors made with Python's gzip.compress(data, mtime=0).
+    const FIXED: &str = "1f8b08000000000002034bcf2c51282e492c292de60200a56862d00b000000";
+    const STORED: &str = "1f8b0800000000000403010700f8ff6c73202d6c610adb5fab1207000000";
+
+    #[test]
+    fn decodes_fixed_and_stored_blocks() {
+        assert_eq!(decompress(&hex(FIXED)[..]).unwrap(), b"git status\n");
+        assert_eq!(decompress(&hex(STORED)[..]).unwrap(), b"ls -la\n");
+    }
+
+    #[test]
+    fn decodes_dynamic_blocks() {
+        let data = hex(
+            "1f8b080000000000020375cf2b0ec020140551df553c8722e9edbfeb690241bc602a583ef84ed524c7\
+             4d2eaf3dd57d24ba85549acd61ca5f15ea82baa26ea83bea817aa25ea8375ffcccf19d784ffc271e14\
+             1f8a17351e3b3ceb6d5394010000",
+        );
+        let expected: String = (0..18).map(|i| format!("git commit -m 'fix {i}'\n")).collect();
+        assert_eq!(decompress(&data[..]).unwrap(), expected.as_bytes());
+    }
+
+    #[test]
+    fn concatenated_members_are_joined() {
+        let data = [hex(FIXED), hex(STORED)].concat();
+        assert_eq!(decompress(&data[..]).unwrap(), b"git status\nls -la\n");
+    }
+
+    #[test]
+    fn rejects_truncated_and_corrupt_data() {
+        let data = hex(FIXED);
+        assert!(decompress(&data[..data.len() - 3]).is_err());
+        let mut corrupt = data.clone();
+        corrupt[12] ^= 0x40;
+        assert!(decompress(&corrupt[..]).is_err());
+        assert!(decompress(&b"plain text"[..]).is_err());
+    }
+}
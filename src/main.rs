@@ -1,34 +1,185 @@
-use regex::Regex;
-use rusqlite::{params, Connection, OptionalExtension};
+#[cfg(feature = "encryption")]
+mod aead;
+mod gzip;
+
+use regex::{Regex, RegexBuilder};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, TransactionBehavior};
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, IsTerminal, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const DB_CAP: usize = 200;
+const PROJECT_DB_NAME: &str = ".memo.sqlite3";
 const DEFAULT_LIMIT: usize = 10;
+/// `--ndjson` output is flushed after this many rows.
+const NDJSON_FLUSH_EVERY: usize = 64;
 
-fn state_db_path() -> PathBuf {
-    let base = env::var_os("XDG_STATE_HOME")
-        .map(PathBuf::from)
-        .unwrap_or_else(|| PathBuf::from("~/.local/state"));
-    let base = if base.to_string_lossy().starts_with("~") {
-        let home = env::var_os("HOME").unwrap_or_default();
-        PathBuf::from(home).join(base.to_string_lossy().trim_start_matches("~/"))
-    } else {
-        base
+const EXIT_NOT_FOUND: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_AMBIGUOUS: i32 = 3;
+const EXIT_COPY_FALLBACK: i32 = 4;
+/// `memo resolve` found nothing, kept apart from 1 so scripts can tell it
+/// from a database or I/O error.
+const EXIT_UNRESOLVED: i32 = 5;
+const EXIT_CANNOT_EXEC: i32 = 126;
+const EXIT_SHELL_NOT_FOUND: i32 = 127;
+
+/// `println!` for memo's output: a reader that closes the pipe early
+/// (`memo list | head`) ends memo quietly with exit 0 instead of a panic.
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        write_stdout_line(format_args!($($arg)*))
+    };
+}
+
+fn write_stdout_line(args: std::fmt::Arguments) {
+    let mut stdout = io::stdout().lock();
+    if let Err(err) = stdout.write_fmt(args).and_then(|()| stdout.write_all(b"\n")) {
+        if err.kind() == io::ErrorKind::BrokenPipe {
+            std::process::exit(0);
+        }
+        eprintln!("write error: {err}");
+        std::process::exit(1);
+    }
+}
+
+fn project_db_path() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_DB_NAME))
+        .find(|candidate| candidate.is_file())
+}
+
+/// The project database when asked for and found, else `$MEMO_DB`, else
+/// `memo.sqlite3` in the state directory.
+fn state_db_path(local: bool) -> PathBuf {
+    let local = local || env::var("MEMO_PROJECT_DB").is_ok_and(|v| v == "1");
+    if local {
+        if let Some(path) = project_db_path() {
+            return path;
+        }
+    }
+    if let Some(path) = env::var("MEMO_DB").ok().filter(|p| !p.is_empty()) {
+        let path = expand_home(&path);
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        return path;
+    }
+    state_dir().join("memo.sqlite3")
+}
+
+/// An XDG base directory: `$var` when set and not empty, else `default`.
+/// Both may start with `~`.
+fn xdg_dir(var: &str, default: &str) -> PathBuf {
+    let base = env::var(var).ok().filter(|v| !v.is_empty());
+    expand_home(base.as_deref().unwrap_or(default))
+}
+
+fn state_dir() -> PathBuf {
+    let dir = xdg_dir("XDG_STATE_HOME", "~/.local/state").join("memo");
+    let _ = fs::create_dir_all(&dir);
+    dir
+}
+
+fn cache_dir() -> PathBuf {
+    let dir = xdg_dir("XDG_CACHE_HOME", "~/.cache").join("memo");
+    let _ = fs::create_dir_all(&dir);
+    dir
+}
+
+/// `$XDG_CONFIG_HOME/memo` (fallback `~/.config/memo`). Not created; memo
+/// only reads files the user puts there.
+fn config_dir() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", "~/.config").join("memo")
+}
+
+/// Set by `--read-only` or `$MEMO_READONLY=1`: the database is opened
+/// read-only and every command that would write to it is refused.
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+fn read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// Set by `--json` before the subcommand (`memo --json list`); the same as
+/// passing `--json` to it.
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// In read-only mode, says that `what` was not done and returns true.
+fn refuse_write(what: &str) -> bool {
+    if read_only() {
+        eprintln!("read-only mode: {what} would modify the database");
+    }
+    read_only()
+}
+
+fn connect_db(local: bool) -> Result<Connection, String> {
+    if read_only() {
+        // No schema setup: that would be a write. A database that is missing
+        // migrations is refused up front rather than failing query by query.
+        let conn = Connection::open_with_flags(
+            state_db_path(local),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(|err| err.to_string())?;
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(|err| err.to_string())?;
+        if version < MIGRATIONS.len() {
+            return Err("database needs upgrade; run once without --read-only".to_string());
+        }
+        register_plain_cmd(&conn).map_err(|err| err.to_string())?;
+        return Ok(conn);
+    }
+    let mut conn = Connection::open(state_db_path(local)).map_err(|err| err.to_string())?;
+    migrate(&mut conn).map_err(|err| err.to_string())?;
+    register_plain_cmd(&conn).map_err(|err| err.to_string())?;
+    Ok(conn)
+}
+
+/// Schema migrations, in order. `PRAGMA user_version` records how many a
+/// database has had; a schema change is a new step at the end, never an edit
+/// to an old one.
+const MIGRATIONS: &[fn(&Connection) -> rusqlite::Result<()>] =
+    &[migrate_unversioned, migrate_uses, migrate_detach_steps, migrate_cmd_nonce];
+
+/// Brings the database up to the newest schema. All pending steps run in one
+/// immediate transaction, so two memo processes can't migrate at once.
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version = |conn: &Connection| {
+        conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))
+            .map(|version| version as usize)
     };
-    let db_path = base.join("memo").join("memo.sqlite3");
-    if let Some(parent) = db_path.parent() {
-        let _ = fs::create_dir_all(parent);
+    if version(conn)? >= MIGRATIONS.len() {
+        return Ok(());
+    }
+    let _signals = DeferSignals::new();
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let from = version(&tx)?;
+    for (step, migration) in MIGRATIONS.iter().enumerate().skip(from) {
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", (step + 1) as i64)?;
     }
-    db_path
+    tx.commit()
 }
 
-fn connect_db() -> rusqlite::Result<Connection> {
-    let conn = Connection::open(state_db_path())?;
+/// Step 1: the schema as it stood before versioning. Databases from that
+/// time may have any subset of these columns, so each one is checked.
+fn migrate_unversioned(conn: &Connection) -> rusqlite::Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS memos (\
          id INTEGER PRIMARY KEY AUTOINCREMENT, \
@@ -36,227 +187,4257 @@ fn connect_db() -> rusqlite::Result<Connection> {
          created_at INTEGER NOT NULL)",
         [],
     )?;
-    Ok(conn)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS meta (\
+         key TEXT PRIMARY KEY, \
+         value TEXT NOT NULL)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sequences (\
+         name TEXT NOT NULL, \
+         position INTEGER NOT NULL, \
+         memo_id INTEGER NOT NULL, \
+         PRIMARY KEY (name, position))",
+        [],
+    )?;
+    ensure_column(conn, "tags", "TEXT NOT NULL DEFAULT ''")?;
+    ensure_column(conn, "note", "TEXT NOT NULL DEFAULT ''")?;
+    ensure_column(conn, "source", "TEXT NOT NULL DEFAULT ''")?;
+    ensure_column(conn, "repo", "TEXT NOT NULL DEFAULT ''")?;
+    ensure_column(conn, "branch", "TEXT NOT NULL DEFAULT ''")?;
+    ensure_column(conn, "cwd", "TEXT NOT NULL DEFAULT ''")?;
+    ensure_column(conn, "last_peak_rss", "INTEGER")?;
+    ensure_column(conn, "pinned", "INTEGER NOT NULL DEFAULT 0")
+}
+
+/// Step 2: how often each memo was copied or run, for `memo top`.
+fn migrate_uses(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute("ALTER TABLE memos ADD COLUMN uses INTEGER NOT NULL DEFAULT 0", [])?;
+    Ok(())
+}
+
+/// Step 3: a deleted memo's sequence steps point at id 0, which no memo has,
+/// so `run-seq` reports them as deleted. Left pointing at the old id, they
+/// would run whatever command later got that id.
+fn migrate_detach_steps(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "UPDATE sequences SET memo_id = 0 WHERE memo_id NOT IN (SELECT id FROM memos);
+         CREATE TRIGGER detach_deleted_steps AFTER DELETE ON memos BEGIN
+             UPDATE sequences SET memo_id = 0 WHERE memo_id = OLD.id;
+         END;",
+    )
+}
+
+/// Step 4: the nonce an encrypted command was sealed with; NULL for a
+/// command stored as plain text.
+fn migrate_cmd_nonce(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute("ALTER TABLE memos ADD COLUMN nonce BLOB", [])?;
+    Ok(())
+}
+
+/// Registers `plain_cmd(cmd, nonce)`, which returns a stored command as text
+/// and decrypts it first if it has a nonce, and the temporary `plain_memos`
+/// view, which is `memos` with every command readable. Queries that show or
+/// compare commands read from the view.
+fn register_plain_cmd(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "plain_cmd",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| match ctx.get_raw(1) {
+            ValueRef::Null => ctx.get::<String>(0),
+            nonce => open_cmd(ctx.get_raw(0).as_bytes()?, nonce.as_bytes()?)
+                .map_err(|err| rusqlite::Error::UserFunctionError(err.into())),
+        },
+    )?;
+    conn.execute_batch(
+        "CREATE TEMP VIEW IF NOT EXISTS plain_memos AS \
+         SELECT id, plain_cmd(cmd, nonce) AS cmd, created_at, tags, note, source, repo, \
+         branch, cwd, last_peak_rss, pinned, uses FROM main.memos",
+    )
 }
 
-fn enforce_cap(conn: &Connection) -> rusqlite::Result<()> {
-    let count: i64 = conn.query_row("SELECT COUNT(*) FROM memos", [], |row| row.get(0))?;
-    if count as usize <= DB_CAP {
+/// The key saved commands are encrypted with: 64 hex digits in `$MEMO_KEY`,
+/// or in the file `$MEMO_KEY_FILE` names. None when neither is set.
+#[cfg(feature = "encryption")]
+fn cmd_key() -> Result<Option<[u8; aead::KEY_LEN]>, String> {
+    static KEY: OnceLock<Result<Option<[u8; aead::KEY_LEN]>, String>> = OnceLock::new();
+    KEY.get_or_init(|| {
+        let (text, source) = if let Some(key) = env::var("MEMO_KEY").ok().filter(|k| !k.is_empty())
+        {
+            (key, "MEMO_KEY".to_string())
+        } else if let Some(path) = env::var("MEMO_KEY_FILE").ok().filter(|p| !p.is_empty()) {
+            let path = expand_home(&path);
+            let text = fs::read_to_string(&path)
+                .map_err(|err| format!("cannot read key file {}: {err}", path.display()))?;
+            (text, path.display().to_string())
+        } else {
+            return Ok(None);
+        };
+        let text = text.trim();
+        let mut key = [0u8; aead::KEY_LEN];
+        let valid = text.len() == key.len() * 2
+            && key.iter_mut().enumerate().all(|(i, byte)| {
+                u8::from_str_radix(text.get(i * 2..i * 2 + 2).unwrap_or("-"), 16)
+                    .map(|b| *byte = b)
+                    .is_ok()
+            });
+        if !valid {
+            return Err(format!("invalid key in {source}: expected 64 hex digits"));
+        }
+        Ok(Some(key))
+    })
+    .clone()
+}
+
+/// The `cmd` and `nonce` values to store for a command: sealed under a fresh
+/// random nonce when a key is set, else the text itself and no nonce.
+fn seal_cmd(cmd: &str) -> rusqlite::Result<(Value, Option<Vec<u8>>)> {
+    #[cfg(feature = "encryption")]
+    if let Some(key) = cmd_key().ok().flatten() {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        fs::File::open("/dev/urandom")
+            .and_then(|mut random| random.read_exact(&mut nonce))
+            .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+        let sealed = aead::seal(&key, &nonce, cmd.as_bytes());
+        return Ok((Value::Blob(sealed), Some(nonce.to_vec())));
+    }
+    Ok((Value::Text(cmd.to_string()), None))
+}
+
+/// Decrypts a command stored by seal_cmd.
+#[cfg(feature = "encryption")]
+fn open_cmd(sealed: &[u8], nonce: &[u8]) -> Result<String, String> {
+    let Some(key) = cmd_key()? else {
+        return Err("saved commands are encrypted; set MEMO_KEY or MEMO_KEY_FILE".to_string());
+    };
+    let nonce = nonce.try_into().map_err(|_| "corrupt nonce on a saved command".to_string())?;
+    let plain = aead::open(&key, nonce, sealed)
+        .ok_or_else(|| "cannot decrypt saved commands: wrong key".to_string())?;
+    String::from_utf8(plain).map_err(|_| "a decrypted command is not UTF-8".to_string())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn open_cmd(_sealed: &[u8], _nonce: &[u8]) -> Result<String, String> {
+    Err("saved commands are encrypted; this memo was built without encryption".to_string())
+}
+
+/// Adds a column to `memos` if an unversioned database doesn't have it yet.
+fn ensure_column(conn: &Connection, name: &str, decl: &str) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare("SELECT 1 FROM pragma_table_info('memos') WHERE name = ?")?;
+    if !stmt.exists(params![name])? {
+        conn.execute(&format!("ALTER TABLE memos ADD COLUMN {name} {decl}"), [])?;
+    }
+    Ok(())
+}
+
+fn meta_get(conn: &Connection, key: &str) -> rusqlite::Result<Option<String>> {
+    conn.query_row("SELECT value FROM meta WHERE key = ?", params![key], |row| row.get(0))
+        .optional()
+}
+
+fn meta_set(conn: &Connection, key: &str, value: &str) -> rusqlite::Result<()> {
+    let _signals = DeferSignals::new();
+    conn.execute(
+        "INSERT INTO meta (key, value) VALUES (?, ?) \
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+/// Guards history auto-saves against misbehaving prompt hooks: with
+/// `$MEMO_MIN_SAVE_INTERVAL_MS` set, a save arriving sooner than that after
+/// the previous one is skipped.
+fn auto_save_allowed(conn: &Connection) -> bool {
+    let Some(interval) = env::var("MEMO_MIN_SAVE_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0)
+    else {
+        return true;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let last = meta_get(conn, "last_auto_save_ms")
+        .ok()
+        .flatten()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    if now.saturating_sub(last) < interval {
+        return false;
+    }
+    let _ = meta_set(conn, "last_auto_save_ms", &now.to_string());
+    true
+}
+
+/// How many memos to keep: `$MEMO_CAP`, or DB_CAP when unset; None when
+/// `MEMO_CAP=0` asks for no limit. Any other value that isn't a positive
+/// number is ignored with a warning, so a typo can never make enforce_cap
+/// delete everything.
+fn db_cap() -> Option<usize> {
+    static CAP: OnceLock<Option<usize>> = OnceLock::new();
+    *CAP.get_or_init(|| {
+        env::var("MEMO_CAP").map_or(Some(DB_CAP), |value| parse_cap(&value))
+    })
+}
+
+/// A `MEMO_CAP` value: 0 is unbounded (None), anything but a positive
+/// number falls back to the default with a warning.
+fn parse_cap(value: &str) -> Option<usize> {
+    match value.trim().parse::<i64>() {
+        Ok(0) => None,
+        Ok(n) if n > 0 => Some(n as usize),
+        _ => {
+            eprintln!(
+                "warning: ignoring MEMO_CAP={value}, expected 0 or a positive number; \
+                 using {DB_CAP}"
+            );
+            Some(DB_CAP)
+        }
+    }
+}
+
+/// Pinned memos are never evicted and don't count toward the cap.
+fn enforce_cap(conn: &Connection, cap: Option<usize>) -> rusqlite::Result<()> {
+    let Some(cap) = cap else {
+        return Ok(());
+    };
+    let count: i64 =
+        conn.query_row("SELECT COUNT(*) FROM memos WHERE pinned = 0", [], |row| row.get(0))?;
+    if count as usize <= cap {
         return Ok(());
     }
-    let to_delete = count - DB_CAP as i64;
+    let to_delete = count - cap as i64;
     conn.execute(
         "DELETE FROM memos WHERE id IN (\
-         SELECT id FROM memos ORDER BY id ASC LIMIT ?)",
+         SELECT id FROM memos WHERE pinned = 0 ORDER BY created_at, id LIMIT ?)",
         params![to_delete],
     )?;
     Ok(())
 }
 
-fn insert_cmd(conn: &Connection, cmd: &str) -> rusqlite::Result<()> {
-    let now = SystemTime::now()
+fn now_secs() -> i64 {
+    SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default()
-        .as_secs() as i64;
+        .as_secs() as i64
+}
+
+fn insert_row(
+    conn: &Connection,
+    cmd: &str,
+    created_at: i64,
+    source: &str,
+    git: Option<&GitContext>,
+    cwd: &str,
+) -> rusqlite::Result<()> {
+    let (repo, branch) = git.map_or(("", ""), |g| (g.repo.as_str(), g.branch.as_str()));
+    let (cmd, nonce) = seal_cmd(cmd)?;
+    conn.execute(
+        "INSERT INTO memos (cmd, nonce, created_at, source, repo, branch, cwd) \
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+        params![cmd, nonce, created_at, source, repo, branch, cwd],
+    )?;
+    Ok(())
+}
+
+/// Applies the secrets policy to a command about to be stored. Returns None
+/// when the policy says to skip it.
+fn apply_secrets(cmd: &str, secrets: Option<SecretsMode>) -> Option<String> {
+    let cmd = match secrets {
+        Some(mode) if has_secret(cmd) => match mode {
+            SecretsMode::Warn => {
+                eprintln!("warning: possible secret in saved command");
+                cmd.to_string()
+            }
+            SecretsMode::Skip => {
+                eprintln!("warning: possible secret in command, not saved");
+                return None;
+            }
+            SecretsMode::Mask => {
+                eprintln!("warning: possible secret masked in saved command");
+                mask_secrets(cmd)
+            }
+        },
+        _ => cmd.to_string(),
+    };
+    Some(cmd)
+}
+
+/// How a command gets stored. `created_at` defaults to now; `source` records
+/// where the command came from (`manual`, `history`, `import`, ...).
+struct SaveOpts {
+    secrets: Option<SecretsMode>,
+    created_at: Option<i64>,
+    source: String,
+    git: Option<GitContext>,
+    cwd: String,
+    trim_amp: bool,
+    /// Move an existing copy of the command to the top instead of adding one.
+    dedup: bool,
+}
+
+impl SaveOpts {
+    /// Git context is captured by default when MEMO_GIT_CONTEXT=1, a
+    /// trailing `&` is trimmed by default when MEMO_TRIM_TRAILING_AMP=1, and
+    /// saves dedup by default when MEMO_DEDUP=1.
+    fn new(source: &str) -> Self {
+        let git_default = env::var("MEMO_GIT_CONTEXT").is_ok_and(|v| v == "1");
+        SaveOpts {
+            trim_amp: env::var("MEMO_TRIM_TRAILING_AMP").is_ok_and(|v| v == "1"),
+            dedup: env::var("MEMO_DEDUP").is_ok_and(|v| v == "1"),
+            secrets: secrets_mode(),
+            created_at: None,
+            source: source.to_string(),
+            git: if git_default { git_context() } else { None },
+            cwd: current_dir_string(),
+        }
+    }
+}
+
+/// The working directory as stored in the `cwd` column; empty if unknown.
+fn current_dir_string() -> String {
+    env::current_dir()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The repository (top-level directory name) and branch a command was saved
+/// in.
+struct GitContext {
+    repo: String,
+    branch: String,
+}
+
+/// Asks git about the current directory; None outside a repository or when
+/// git isn't installed. A detached HEAD is stored as `HEAD`.
+fn git_context() -> Option<GitContext> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--show-toplevel", "--abbrev-ref", "HEAD"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|out| out.status.success())?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    let toplevel = Path::new(lines.next()?);
+    let repo = toplevel.file_name()?.to_string_lossy().into_owned();
+    let branch = lines.next().unwrap_or_default().to_string();
+    Some(GitContext { repo, branch })
+}
+
+/// Saves a command, applying the secrets policy first. Returns false when the
+/// policy skipped the command.
+fn insert_cmd(conn: &Connection, cmd: &str, opts: &SaveOpts) -> rusqlite::Result<bool> {
+    let Some(cmd) = apply_secrets(cmd, opts.secrets) else {
+        return Ok(false);
+    };
+    let created_at = opts.created_at.unwrap_or_else(now_secs);
+    let _signals = DeferSignals::new();
+    if opts.dedup {
+        let ids = conn
+            .prepare("SELECT id FROM plain_memos WHERE cmd = ? ORDER BY created_at DESC, id DESC")?
+            .query_map(params![cmd], |row| row.get::<_, i64>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if let Some((&newest, older)) = ids.split_first() {
+            // The row count only shrinks, so there is no cap to enforce. A
+            // savepoint keeps the fold whole inside a caller's transaction.
+            conn.execute_batch("SAVEPOINT dedup")?;
+            let folded = fold_duplicates(conn, newest, older, created_at, opts);
+            conn.execute_batch(if folded.is_ok() {
+                "RELEASE dedup"
+            } else {
+                "ROLLBACK TO dedup; RELEASE dedup"
+            })?;
+            folded?;
+            return Ok(true);
+        }
+    }
+    insert_row(conn, &cmd, created_at, &opts.source, opts.git.as_ref(), &opts.cwd)?;
+    enforce_cap(conn, db_cap())?;
+    Ok(true)
+}
+
+/// Folds the `older` copies of a command into its newest row `keep` and
+/// moves that row to the top. Uses are added up, tags combined, the newest
+/// non-empty note kept and the pin kept if any copy had one; sequence steps
+/// on the older copies follow to the kept row.
+fn fold_duplicates(
+    conn: &Connection,
+    keep: i64,
+    older: &[i64],
+    created_at: i64,
+    opts: &SaveOpts,
+) -> rusqlite::Result<()> {
+    let mut tags: Vec<String> = Vec::new();
+    let (mut note, mut pinned, mut uses) = (String::new(), false, 0i64);
+    for &id in std::iter::once(&keep).chain(older) {
+        let (row_tags, row_note, row_pinned, row_uses): (String, String, bool, i64) = conn
+            .query_row(
+                "SELECT tags, note, pinned, uses FROM memos WHERE id = ?",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )?;
+        for tag in split_tags(&row_tags) {
+            if !tags.iter().any(|t| t == tag) {
+                tags.push(tag.to_string());
+            }
+        }
+        if note.is_empty() {
+            note = row_note;
+        }
+        pinned |= row_pinned;
+        uses += row_uses;
+    }
+    for &id in older {
+        conn.execute("UPDATE sequences SET memo_id = ? WHERE memo_id = ?", params![keep, id])?;
+        conn.execute("DELETE FROM memos WHERE id = ?", params![id])?;
+    }
+    let id = touch_row(conn, keep, created_at)?;
     conn.execute(
-        "INSERT INTO memos (cmd, created_at) VALUES (?, ?)",
-        params![cmd, now],
+        "UPDATE memos SET tags = ?, note = ?, pinned = ?, uses = ?, \
+         source = ?, repo = ?, branch = ?, cwd = ? WHERE id = ?",
+        params![
+            tags.join(","),
+            note,
+            pinned,
+            uses,
+            opts.source,
+            opts.git.as_ref().map_or("", |g| g.repo.as_str()),
+            opts.git.as_ref().map_or("", |g| g.branch.as_str()),
+            opts.cwd,
+            id,
+        ],
     )?;
-    enforce_cap(conn)?;
     Ok(())
 }
 
+/// Overwrites the newest memo's command in place. Returns false when there
+/// is no memo to replace or the secrets policy skipped the command.
+fn replace_last_cmd(conn: &Connection, cmd: &str, opts: &SaveOpts) -> rusqlite::Result<bool> {
+    let Some(cmd) = apply_secrets(cmd, opts.secrets) else {
+        return Ok(false);
+    };
+    let (cmd, nonce) = seal_cmd(&cmd)?;
+    let _signals = DeferSignals::new();
+    let changed = conn.execute(
+        "UPDATE memos SET cmd = ?, nonce = ?, created_at = ?, source = ?, repo = ?, branch = ?, \
+         cwd = ? WHERE id = (SELECT id FROM memos ORDER BY created_at DESC, id DESC LIMIT 1)",
+        params![
+            cmd,
+            nonce,
+            opts.created_at.unwrap_or_else(now_secs),
+            opts.source,
+            opts.git.as_ref().map_or("", |g| g.repo.as_str()),
+            opts.git.as_ref().map_or("", |g| g.branch.as_str()),
+            opts.cwd,
+        ],
+    )?;
+    Ok(changed > 0)
+}
+
+/// Counts a copy or run of `cmd` on its newest saved copy, for `memo top`.
+/// Read-only mode skips it quietly: the count is bookkeeping, not something
+/// the user asked to write.
+fn record_use(conn: &Connection, cmd: &str) {
+    if read_only() {
+        return;
+    }
+    let _signals = DeferSignals::new();
+    let _ = conn.execute(
+        "UPDATE memos SET uses = uses + 1 WHERE id = (\
+         SELECT id FROM plain_memos WHERE cmd = ? ORDER BY created_at DESC, id DESC LIMIT 1)",
+        params![cmd],
+    );
+}
+
+fn cmd_exists(conn: &Connection, cmd: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM plain_memos WHERE cmd = ?)",
+        params![cmd],
+        |row| row.get(0),
+    )
+}
+
+/// Copies rows from another memo database, oldest first, skipping commands
+/// already present. Returns (added, skipped).
+fn merge_db(conn: &mut Connection, other: &Path) -> rusqlite::Result<(usize, usize)> {
+    let src = Connection::open_with_flags(other, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    // Databases from before encryption have no nonce column.
+    let encrypted = src
+        .prepare("SELECT 1 FROM pragma_table_info('memos') WHERE name = 'nonce'")?
+        .exists([])?;
+    let cmd = if encrypted {
+        register_plain_cmd(&src)?;
+        "plain_cmd(cmd, nonce)"
+    } else {
+        "cmd"
+    };
+    let mut stmt = src.prepare(&format!("SELECT {cmd}, created_at FROM memos ORDER BY id ASC"))?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let _signals = DeferSignals::new();
+    let tx = conn.transaction()?;
+    let mut added = 0;
+    let mut skipped = 0;
+    for (cmd, created_at) in rows {
+        if cmd_exists(&tx, &cmd)? {
+            skipped += 1;
+            continue;
+        }
+        insert_row(&tx, &cmd, created_at, "import", None, "")?;
+        added += 1;
+    }
+    enforce_cap(&tx, db_cap())?;
+    tx.commit()?;
+    Ok((added, skipped))
+}
+
+/// Adds shell history entries, oldest first. Each command is added once, at
+/// its newest occurrence, and commands already saved are skipped. Returns
+/// (added, skipped).
+fn import_history(
+    conn: &mut Connection,
+    entries: Vec<HistoryEntry>,
+) -> rusqlite::Result<(usize, usize)> {
+    let mut last_seen = std::collections::HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        last_seen.insert(entry.cmd.as_str(), i);
+    }
+    // Built once: SaveOpts::new reads the environment and may run git.
+    let mut save = SaveOpts::new("import");
+    save.git = None;
+    save.cwd = String::new();
+    let _signals = DeferSignals::new();
+    let tx = conn.transaction()?;
+    let mut added = 0;
+    let mut skipped = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        if last_seen[entry.cmd.as_str()] != i || cmd_exists(&tx, &entry.cmd)? {
+            skipped += 1;
+            continue;
+        }
+        save.created_at = entry.timestamp;
+        if insert_cmd(&tx, &entry.cmd, &save)? {
+            added += 1;
+        } else {
+            skipped += 1;
+        }
+    }
+    tx.commit()?;
+    Ok((added, skipped))
+}
+
+fn last_saved_cwd(conn: &Connection) -> rusqlite::Result<Option<String>> {
+    conn.query_row(
+        "SELECT cwd FROM memos ORDER BY created_at DESC, id DESC LIMIT 1",
+        [],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
 fn last_saved_cmd(conn: &Connection) -> rusqlite::Result<Option<String>> {
     conn.query_row(
-        "SELECT cmd FROM memos ORDER BY id DESC LIMIT 1",
+        "SELECT cmd FROM plain_memos ORDER BY created_at DESC, id DESC LIMIT 1",
         [],
         |row| row.get(0),
     )
     .optional()
 }
 
-fn list_cmds(conn: &Connection, limit: usize, query: Option<&str>) -> rusqlite::Result<Vec<(usize, String)>> {
-    let mut stmt = conn.prepare("SELECT cmd FROM memos ORDER BY id DESC")?;
-    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+struct Memo {
+    index: usize,
+    id: i64,
+    cmd: String,
+    created_at: i64,
+    tags: String,
+    note: String,
+    source: String,
+    repo: String,
+    branch: String,
+    cwd: String,
+    /// Bytes, from the last `run --measure-and-store-peak-rss`.
+    peak_rss: Option<i64>,
+    pinned: bool,
+    /// Times copied or run.
+    uses: i64,
+}
 
-    let mut out = Vec::new();
-    let mut idx = 1usize;
-    let query = query.map(|q| q.to_lowercase());
-    for row in rows {
-        let cmd = row?;
-        let matched = match &query {
-            Some(q) => cmd.to_lowercase().contains(q),
-            None => true,
+impl Memo {
+    fn tag_list(&self) -> Vec<&str> {
+        split_tags(&self.tags)
+    }
+}
+
+/// Tags are stored as one comma-separated string.
+fn split_tags(tags: &str) -> Vec<&str> {
+    tags.split(',').map(str::trim).filter(|t| !t.is_empty()).collect()
+}
+
+struct ListOpts {
+    limit: usize,
+    skip: usize,
+    query: Option<String>,
+    distinct: bool,
+    oldest: bool,
+    since_id: Option<i64>,
+    json: bool,
+    ndjson: bool,
+    tags_any: Vec<String>,
+    tags_all: Vec<String>,
+    out: Option<PathBuf>,
+    time: Option<TimeStyle>,
+    rss: bool,
+    show_cwd: bool,
+    word: bool,
+    regex: bool,
+    fuzzy: bool,
+    /// The query compiled for `--regex`; see compile_query.
+    query_regex: Option<Regex>,
+    relative_paths: bool,
+    files: bool,
+    case: CaseMode,
+    source: Option<String>,
+    repo: Option<String>,
+    branch: Option<String>,
+    fields: Vec<Field>,
+    field_widths: Vec<(Field, usize)>,
+    ignore: Vec<Regex>,
+    existing_binary: bool,
+    no_dangerous: bool,
+    last_query: bool,
+    clear_query: bool,
+    json_schema: bool,
+}
+
+/// How `--time` shows when a command was saved.
+#[derive(Clone, Copy)]
+enum TimeStyle {
+    /// `5m ago`
+    Relative,
+    /// `2024-05-01T14:03:22+02:00`, local time
+    Iso,
+}
+
+/// A column of the `--fields` table.
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Index,
+    Id,
+    Cmd,
+    Age,
+    Created,
+    Tags,
+    Note,
+    Source,
+    Hash,
+    Repo,
+    Branch,
+    Cwd,
+    Rss,
+}
+
+/// Columns used when only `--fields-width` is given.
+const DEFAULT_FIELDS: [Field; 4] = [Field::Index, Field::Cmd, Field::Tags, Field::Note];
+
+impl Field {
+    const ALL: [Field; 13] = [
+        Field::Index,
+        Field::Id,
+        Field::Cmd,
+        Field::Age,
+        Field::Created,
+        Field::Tags,
+        Field::Note,
+        Field::Source,
+        Field::Hash,
+        Field::Repo,
+        Field::Branch,
+        Field::Cwd,
+        Field::Rss,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Field::Index => "index",
+            Field::Id => "id",
+            Field::Cmd => "cmd",
+            Field::Age => "age",
+            Field::Created => "created",
+            Field::Tags => "tags",
+            Field::Note => "note",
+            Field::Source => "source",
+            Field::Hash => "hash",
+            Field::Repo => "repo",
+            Field::Branch => "branch",
+            Field::Cwd => "cwd",
+            Field::Rss => "rss",
+        }
+    }
+
+    fn parse(name: &str) -> Result<Field, String> {
+        Field::ALL
+            .into_iter()
+            .find(|f| f.name() == name.trim())
+            .ok_or_else(|| {
+                let names: Vec<_> = Field::ALL.iter().map(|f| f.name()).collect();
+                format!("unknown field: {name} (expected {})", names.join(", "))
+            })
+    }
+
+    fn value(self, memo: &Memo, now: i64) -> String {
+        match self {
+            Field::Index => memo.index.to_string(),
+            Field::Id => memo.id.to_string(),
+            Field::Cmd => memo.cmd.clone(),
+            Field::Age => format_age(memo.created_at, now),
+            Field::Created => memo.created_at.to_string(),
+            Field::Tags => memo.tag_list().join(","),
+            Field::Note => memo.note.clone(),
+            Field::Source => memo.source.clone(),
+            Field::Hash => short_hash(&memo.cmd),
+            Field::Repo => memo.repo.clone(),
+            Field::Branch => memo.branch.clone(),
+            Field::Cwd => memo.cwd.clone(),
+            Field::Rss => memo.peak_rss.map(format_bytes).unwrap_or_default(),
+        }
+    }
+}
+
+/// Applies `--last-query` and `--clear-query`, then remembers this listing's
+/// query for next time. `--last-query` with new words refines the previous
+/// query. Returns Some(exit code) when there is nothing left to list.
+fn recall_query(conn: &Connection, opts: &mut ListOpts) -> Option<i32> {
+    if opts.clear_query {
+        if refuse_write("--clear-query") {
+            return Some(1);
+        }
+        let _signals = DeferSignals::new();
+        if let Err(err) = conn.execute("DELETE FROM meta WHERE key = 'last_query'", []) {
+            eprintln!("db error: {err}");
+            return Some(1);
+        }
+        outln!("cleared last query");
+        return Some(0);
+    }
+    if opts.last_query {
+        let Some(last) = meta_get(conn, "last_query").ok().flatten() else {
+            eprintln!("no previous query");
+            return Some(EXIT_NOT_FOUND);
         };
-        if matched {
-            out.push((idx, cmd));
-            if out.len() >= limit {
-                break;
-            }
+        opts.query = Some(match opts.query.take() {
+            Some(more) => format!("{last} {more}"),
+            None => last,
+        });
+        if let Err(err) = opts.compile_query() {
+            eprintln!("{err}");
+            return Some(EXIT_USAGE);
         }
-        idx += 1;
     }
-    Ok(out)
+    if let Some(query) = opts.query.as_ref().filter(|_| !read_only()) {
+        let _ = meta_set(conn, "last_query", query);
+    }
+    None
+}
+
+/// Reads `ignore` in the config directory: one regex per line, blank lines
+/// and `#` comments skipped. A missing file ignores nothing.
+fn load_ignore_patterns() -> Result<Vec<Regex>, String> {
+    Ok(load_patterns("ignore")?.unwrap_or_default())
+}
+
+/// Reads a pattern file in the config directory: one regex per line, blank
+/// lines and `#` comments skipped. None when the file doesn't exist.
+fn load_patterns(name: &str) -> Result<Option<Vec<Regex>>, String> {
+    let path = config_dir().join(name);
+    let Ok(content) = fs::read_to_string(&path) else {
+        return Ok(None);
+    };
+    pattern_lines(&content)
+        .map(|line| {
+            Regex::new(line).map_err(|err| format!("{}: bad pattern {line}: {err}", path.display()))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn pattern_lines(content: &str) -> impl Iterator<Item = &str> {
+    content.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Shell builtins and keywords, which never show up on PATH.
+const SHELL_BUILTINS: &[&str] = &[
+    ".", ":", "[", "alias", "bg", "break", "builtin", "case", "cd", "command", "continue",
+    "echo", "eval", "exec", "exit", "export", "false", "fg", "for", "function", "if", "jobs",
+    "kill", "popd", "printf", "pushd", "pwd", "read", "return", "set", "shift", "source",
+    "test", "time", "trap", "true", "type", "ulimit", "umask", "unalias", "unset", "until",
+    "wait", "while", "{", "(",
+];
+
+/// Whether the program a command starts with can be run here: a builtin, an
+/// existing path, or a name found on PATH. Leading `VAR=value` assignments
+/// and `sudo` are skipped.
+fn binary_exists(cmd: &str) -> bool {
+    let Some(program) = command_program(cmd) else {
+        return true;
+    };
+    if SHELL_BUILTINS.contains(&program) {
+        return true;
+    }
+    if program.contains('/') {
+        return expand_home(program).exists();
+    }
+    which(program).is_some()
+}
+
+/// The program a command runs: its first word after `sudo` and any
+/// `NAME=value` environment assignments.
+fn command_program(cmd: &str) -> Option<&str> {
+    cmd.split_whitespace().find(|w| {
+        *w != "sudo"
+            && !w.split_once('=').is_some_and(|(name, _)| {
+                !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+            })
+    })
+}
+
+/// Parses `--fields-width cmd=60,note=20`.
+fn parse_field_widths(value: &str) -> Result<Vec<(Field, usize)>, String> {
+    value
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(|part| {
+            let (name, width) = part
+                .split_once('=')
+                .ok_or_else(|| format!("invalid --fields-width entry: {part}"))?;
+            let width = width
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|w| *w > 0)
+                .ok_or_else(|| format!("invalid width for {name}: {width}"))?;
+            Ok((Field::parse(name)?, width))
+        })
+        .collect()
+}
+
+/// How a listing query is compared against commands.
+#[derive(Clone, Copy, PartialEq)]
+enum CaseMode {
+    Insensitive,
+    Sensitive,
+    /// Insensitive unless the query contains an uppercase letter.
+    Smart,
+}
+
+impl CaseMode {
+    /// Default mode, from MEMO_SMART_CASE (set to 1 to make smart case the default).
+    fn from_env() -> Self {
+        match env::var("MEMO_SMART_CASE").as_deref() {
+            Ok("1") => CaseMode::Smart,
+            _ => CaseMode::Insensitive,
+        }
+    }
+
+    fn is_sensitive(self, needle: &str) -> bool {
+        match self {
+            CaseMode::Insensitive => false,
+            CaseMode::Sensitive => true,
+            CaseMode::Smart => needle.chars().any(char::is_uppercase),
+        }
+    }
+
+    /// With `whole_word`, the match must not be part of a longer word, so
+    /// `git` finds `git log` but not `digit`.
+    fn contains(self, haystack: &str, needle: &str, whole_word: bool) -> bool {
+        let (haystack, needle) = if self.is_sensitive(needle) {
+            (haystack.to_string(), needle.to_string())
+        } else {
+            (haystack.to_lowercase(), needle.to_lowercase())
+        };
+        if !whole_word {
+            return haystack.contains(&needle);
+        }
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        haystack.match_indices(&needle).any(|(start, _)| {
+            let before = haystack[..start].chars().next_back();
+            let after = haystack[start + needle.len()..].chars().next();
+            !before.is_some_and(is_word) && !after.is_some_and(is_word)
+        })
+    }
+}
+
+impl ListOpts {
+    /// Compiles the query for `--regex`, honouring the case options and
+    /// `--word`. Run again whenever the query changes.
+    fn compile_query(&mut self) -> Result<(), String> {
+        self.query_regex = None;
+        let Some(query) = self.query.as_ref().filter(|_| self.regex) else {
+            return Ok(());
+        };
+        let pattern = if self.word { format!(r"\b(?:{query})\b") } else { query.clone() };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(!self.case.is_sensitive(query))
+            .build()
+            .map_err(|err| format!("invalid regex: {err}"))?;
+        self.query_regex = Some(regex);
+        Ok(())
+    }
+
+    fn query_matches(&self, cmd: &str) -> bool {
+        match (&self.query_regex, &self.query) {
+            (Some(re), _) => re.is_match(cmd),
+            (None, Some(q)) if self.fuzzy => self.fuzzy_score(cmd, q).is_some(),
+            (None, Some(q)) => self.case.contains(cmd, q, self.word),
+            (None, None) => true,
+        }
+    }
+
+    fn fuzzy_score(&self, cmd: &str, query: &str) -> Option<usize> {
+        fuzzy_score(cmd, query, self.case.is_sensitive(query))
+    }
+
+    fn matches(&self, memo: &Memo) -> bool {
+        if !self.query_matches(&memo.cmd) {
+            return false;
+        }
+        if self.source.as_ref().is_some_and(|s| *s != memo.source)
+            || self.repo.as_ref().is_some_and(|r| *r != memo.repo)
+            || self.branch.as_ref().is_some_and(|b| *b != memo.branch)
+        {
+            return false;
+        }
+        if self.ignore.iter().any(|re| re.is_match(&memo.cmd))
+            || (self.existing_binary && !binary_exists(&memo.cmd))
+            || (self.no_dangerous && is_dangerous(&memo.cmd))
+        {
+            return false;
+        }
+        let tags = memo.tag_list();
+        if !self.tags_any.is_empty() && !self.tags_any.iter().any(|t| tags.contains(&t.as_str())) {
+            return false;
+        }
+        self.tags_all.iter().all(|t| tags.contains(&t.as_str()))
+    }
+
+    fn new(limit: usize, query: Option<&str>) -> Self {
+        ListOpts {
+            limit,
+            skip: 0,
+            query: query.map(str::to_string),
+            distinct: false,
+            oldest: false,
+            since_id: None,
+            json: false,
+            ndjson: false,
+            tags_any: Vec::new(),
+            tags_all: Vec::new(),
+            out: None,
+            time: None,
+            rss: false,
+            show_cwd: false,
+            word: false,
+            regex: false,
+            fuzzy: false,
+            query_regex: None,
+            relative_paths: false,
+            files: false,
+            case: CaseMode::from_env(),
+            source: None,
+            repo: None,
+            branch: None,
+            fields: Vec::new(),
+            field_widths: Vec::new(),
+            ignore: Vec::new(),
+            existing_binary: false,
+            no_dangerous: false,
+            last_query: false,
+            clear_query: false,
+            json_schema: false,
+        }
+    }
+}
+
+/// Pulls listing flags out of `args`; whatever is left becomes the query.
+fn parse_list_opts(args: &[String], default_limit: usize) -> Result<ListOpts, String> {
+    let mut opts = ListOpts::new(default_limit, None);
+    opts.json = json_output();
+    let mut limited = false;
+    let mut words = Vec::new();
+    let mut iter = args.iter().peekable();
+    while let Some(arg) = iter.next() {
+        // `--flag=value`, or `--flag value` passed as one quoted word.
+        let (flag, inline) = match arg.split_once(['=', ' ']) {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| iter.next().cloned())
+                .ok_or_else(|| format!("missing value for {flag}"))
+        };
+        match flag {
+            "--distinct" => opts.distinct = true,
+            "--time" => {
+                // The style is optional, so a separate word is only taken
+                // when it names one.
+                let style = inline.or_else(|| {
+                    iter.next_if(|next| matches!(next.as_str(), "relative" | "iso")).cloned()
+                });
+                opts.time = match style.as_deref() {
+                    None | Some("relative") => Some(TimeStyle::Relative),
+                    Some("iso") => Some(TimeStyle::Iso),
+                    Some(other) => {
+                        return Err(format!(
+                            "invalid --time: {other} (use --time, --time=relative or --time=iso)"
+                        ))
+                    }
+                }
+            }
+            "--rss" => opts.rss = true,
+            "--show-cwd" => opts.show_cwd = true,
+            "--relative-paths" => opts.relative_paths = true,
+            "--files" => opts.files = true,
+            "--smart-case" => opts.case = CaseMode::Smart,
+            "--case-sensitive" => opts.case = CaseMode::Sensitive,
+            "--ignore-case" => opts.case = CaseMode::Insensitive,
+            "--word" => opts.word = true,
+            "--regex" => opts.regex = true,
+            "--fuzzy" => opts.fuzzy = true,
+            "--limit" => {
+                (opts.limit, opts.skip) = parse_limit(&value()?)?;
+                limited = true;
+            }
+            "--json" => opts.json = true,
+            "--ndjson" | "--jsonl" => opts.ndjson = true,
+            "--source" => opts.source = Some(value()?),
+            "--repo" => opts.repo = Some(value()?),
+            "--branch" => opts.branch = Some(value()?),
+            "--fields" => {
+                opts.fields = value()?
+                    .split(',')
+                    .filter(|f| !f.trim().is_empty())
+                    .map(Field::parse)
+                    .collect::<Result<_, _>>()?;
+            }
+            "--fields-width" => opts.field_widths = parse_field_widths(&value()?)?,
+            "--hide-ignored" => opts.ignore = load_ignore_patterns()?,
+            "--only-existing-binary" => opts.existing_binary = true,
+            "--no-dangerous" => opts.no_dangerous = true,
+            "--last-query" => opts.last_query = true,
+            "--clear-query" => opts.clear_query = true,
+            "--json-schema" => opts.json_schema = true,
+            "--active-only" => {
+                opts.ignore = load_ignore_patterns()?;
+                opts.existing_binary = true;
+            }
+            "--out" => opts.out = Some(expand_home(&value()?)),
+            "--tag" | "--tag-any" => opts
+                .tags_any
+                .extend(split_tags(&value()?).into_iter().map(str::to_string)),
+            "--tag-all" => opts
+                .tags_all
+                .extend(split_tags(&value()?).into_iter().map(str::to_string)),
+            "--since-id" => {
+                let id = value()?;
+                opts.since_id = Some(
+                    id.parse::<i64>()
+                        .map_err(|_| format!("invalid --since-id: {id}"))?,
+                );
+                opts.oldest = true;
+            }
+            "--oldest-n" => {
+                let n = value()?;
+                opts.limit = n
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("invalid --oldest-n: {n}"))?;
+                opts.oldest = true;
+                limited = true;
+            }
+            _ => words.push(arg.as_str()),
+        }
+    }
+    if !words.is_empty() {
+        opts.query = Some(words.join(" "));
+    }
+    // A cursor that silently stopped at the default limit would skip rows.
+    if opts.since_id.is_some() && !limited {
+        opts.limit = usize::MAX;
+    }
+    if opts.fuzzy && (opts.regex || opts.word) {
+        return Err("--fuzzy cannot be combined with --regex or --word".to_string());
+    }
+    opts.compile_query()?;
+    if opts.fields.is_empty() && !opts.field_widths.is_empty() {
+        opts.fields = DEFAULT_FIELDS.to_vec();
+    }
+    Ok(opts)
+}
+
+/// Parses a `--limit` value into (limit, skip). `all` lifts the limit and a
+/// negative value hides that many of the most recent matches.
+fn parse_limit(value: &str) -> Result<(usize, usize), String> {
+    if value == "all" || value == "0" {
+        return Ok((usize::MAX, 0));
+    }
+    match value.parse::<i64>() {
+        Ok(n) if n > 0 => Ok((n as usize, 0)),
+        Ok(n) if n < 0 => Ok((usize::MAX, n.unsigned_abs() as usize)),
+        _ => Err(format!("invalid --limit: {value}")),
+    }
+}
+
+fn list_cmds(conn: &Connection, opts: &ListOpts) -> rusqlite::Result<Vec<Memo>> {
+    let mut out = Vec::new();
+    each_cmd(conn, opts, |memo| {
+        out.push(memo);
+        true
+    })?;
+    Ok(out)
+}
+
+/// Walks the matching rows one at a time, after `--limit` skip and limit are
+/// applied. `visit` returns false to stop early.
+fn each_cmd(
+    conn: &Connection,
+    opts: &ListOpts,
+    mut visit: impl FnMut(Memo) -> bool,
+) -> rusqlite::Result<()> {
+    // Indexes are always newest-first so they work with print/run; --oldest-n
+    // and --since-id only change the order rows are walked in. Newest means
+    // latest created_at, then highest id: merged and imported rows get new
+    // ids but keep their old times, and must not jump ahead. --since-id is a
+    // cursor, so it walks in id order regardless. --distinct keeps each
+    // command's newest row but reports its newest timestamp.
+    let order = if opts.since_id.is_some() {
+        "id ASC"
+    } else if opts.oldest {
+        "idx DESC"
+    } else {
+        "idx ASC"
+    };
+    let created_at = if opts.distinct { "newest_at" } else { "created_at" };
+    let sql = format!(
+        "SELECT idx, id, cmd, {created_at}, tags, note, source, repo, branch, cwd, \
+         last_peak_rss, pinned, uses FROM (\
+         SELECT ROW_NUMBER() OVER (ORDER BY created_at DESC, id DESC) AS idx, \
+         ROW_NUMBER() OVER (PARTITION BY cmd ORDER BY created_at DESC, id DESC) AS dup, \
+         MAX(created_at) OVER (PARTITION BY cmd) AS newest_at, \
+         * FROM plain_memos) \
+         WHERE id > ?1 AND (?2 = 0 OR dup = 1) ORDER BY {order}"
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params![opts.since_id.unwrap_or(0), opts.distinct], |row| {
+        Ok(Memo {
+            index: row.get::<_, i64>(0)? as usize,
+            id: row.get(1)?,
+            cmd: row.get(2)?,
+            created_at: row.get(3)?,
+            tags: row.get(4)?,
+            note: row.get(5)?,
+            source: row.get(6)?,
+            repo: row.get(7)?,
+            branch: row.get(8)?,
+            cwd: row.get(9)?,
+            peak_rss: row.get(10)?,
+            pinned: row.get(11)?,
+            uses: row.get(12)?,
+        })
+    })?;
+
+    let mut matching: Box<dyn Iterator<Item = rusqlite::Result<Memo>>> =
+        Box::new(rows.filter(|row| row.as_ref().map_or(true, |memo| opts.matches(memo))));
+    if let Some(query) = opts.query.as_deref().filter(|_| opts.fuzzy) {
+        // Ranking needs every match before the limit applies. The sort is
+        // stable, so equal scores keep their usual order.
+        let mut ranked = matching.collect::<rusqlite::Result<Vec<_>>>()?;
+        ranked.sort_by_cached_key(|memo| opts.fuzzy_score(&memo.cmd, query));
+        matching = Box::new(ranked.into_iter().map(Ok));
+    }
+
+    let mut seen = 0;
+    let mut skip = opts.skip;
+    for memo in matching {
+        let memo = memo?;
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        seen += 1;
+        if !visit(memo) || seen >= opts.limit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Fuzzy match: `query`'s characters must appear in `cmd` in order, so `gco`
+/// matches `git checkout`. The score is the number of other characters in
+/// the tightest stretch of `cmd` holding them; lower is better. None when
+/// they don't all appear.
+fn fuzzy_score(cmd: &str, query: &str, case_sensitive: bool) -> Option<usize> {
+    let fold = |text: &str| -> Vec<char> {
+        if case_sensitive {
+            text.chars().collect()
+        } else {
+            text.chars().flat_map(char::to_lowercase).collect()
+        }
+    };
+    let (hay, needle) = (fold(cmd), fold(query));
+    let Some(&first) = needle.first() else {
+        return Some(0);
+    };
+    let mut best: Option<usize> = None;
+    for (start, _) in hay.iter().enumerate().filter(|(_, c)| **c == first) {
+        let mut next = 1;
+        let mut end = start;
+        for (i, c) in hay.iter().enumerate().skip(start + 1) {
+            if next == needle.len() {
+                break;
+            }
+            if *c == needle[next] {
+                next += 1;
+                end = i;
+            }
+        }
+        if next < needle.len() {
+            // A later start has even less room, so it can't match either.
+            break;
+        }
+        let gaps = end + 1 - start - needle.len();
+        best = Some(best.map_or(gaps, |best| best.min(gaps)));
+    }
+    best
+}
+
+fn format_age(created_at: i64, now: i64) -> String {
+    let secs = (now - created_at).max(0);
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86_399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86_400),
+    }
+}
+
+/// `1536` -> `1.5 KiB`.
+fn format_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+// libc's struct tm, declared by hand and so only for Linux and macOS, whose
+// layout it matches. Elsewhere local_offset falls back to UTC.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[repr(C)]
+struct Tm {
+    tm_sec: i32,
+    tm_min: i32,
+    tm_hour: i32,
+    tm_mday: i32,
+    tm_mon: i32,
+    tm_year: i32,
+    tm_wday: i32,
+    tm_yday: i32,
+    tm_isdst: i32,
+    tm_gmtoff: std::ffi::c_long,
+    tm_zone: *const std::ffi::c_char,
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+extern "C" {
+    fn tzset();
+    fn localtime_r(time: *const std::ffi::c_long, result: *mut Tm) -> *mut Tm;
+}
+
+/// Seconds east of UTC for the local timezone at `ts`.
+fn local_offset(ts: i64) -> i64 {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        // time_t is a C long on both; where that is 32 bits, times past 2038
+        // wrap.
+        #[allow(clippy::unnecessary_cast)]
+        let time = ts as std::ffi::c_long;
+        let mut tm = std::mem::MaybeUninit::<Tm>::zeroed();
+        // SAFETY: localtime_r only writes into the zeroed Tm we pass it.
+        unsafe {
+            tzset();
+            if !localtime_r(&time, tm.as_mut_ptr()).is_null() {
+                // c_long is only 32 bits on some targets.
+                #[allow(clippy::unnecessary_cast)]
+                return tm.assume_init().tm_gmtoff as i64;
+            }
+        }
+    }
+    0
+}
+
+struct LocalTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    /// Seconds east of UTC.
+    offset: i64,
+}
+
+fn local_time(ts: i64) -> LocalTime {
+    let offset = local_offset(ts);
+    let local = ts + offset;
+    let secs = local.rem_euclid(86_400);
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = local.div_euclid(86_400) + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    LocalTime {
+        year,
+        month,
+        day,
+        hour: (secs / 3600) as u32,
+        minute: (secs / 60 % 60) as u32,
+        second: (secs % 60) as u32,
+        offset,
+    }
+}
+
+/// ISO 8601 local time with its UTC offset, e.g. `2024-05-01T14:03:22+02:00`.
+fn format_iso(ts: i64) -> String {
+    let t = local_time(ts);
+    let sign = if t.offset < 0 { '-' } else { '+' };
+    let offset = t.offset.abs() / 60;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{sign}{:02}:{:02}",
+        t.year,
+        t.month,
+        t.day,
+        t.hour,
+        t.minute,
+        t.second,
+        offset / 60,
+        offset % 60
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// JSON Schema for one row of `--json`/`--ndjson`, printed by
+/// `list --json-schema`. Keep it in step with memo_json. Missing text is an
+/// empty string; only numbers that may never have been measured are nullable.
+const MEMO_JSON_SCHEMA: &str = r#"{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "title": "memo",
+  "description": "One saved command, as printed by memo list --json (array) or --ndjson (lines)",
+  "type": "object",
+  "properties": {
+    "index": {"type": "integer", "minimum": 1, "description": "position, newest first"},
+    "id": {"type": "integer", "description": "stable row id"},
+    "cmd": {"type": "string"},
+    "created_at": {"type": "integer", "description": "Unix seconds"},
+    "tags": {"type": "array", "items": {"type": "string"}},
+    "note": {"type": "string"},
+    "source": {"type": "string", "description": "manual, history, import, or a custom label"},
+    "repo": {"type": "string", "description": "git repository name, empty if not recorded"},
+    "branch": {"type": "string", "description": "git branch, empty if not recorded"},
+    "cwd": {"type": "string", "description": "directory saved in, empty if unknown"},
+    "last_peak_rss": {
+      "type": ["integer", "null"],
+      "description": "peak resident memory in bytes of the last measured run"
+    },
+    "pinned": {"type": "boolean", "description": "exempt from MEMO_CAP eviction"},
+    "uses": {"type": "integer", "minimum": 0, "description": "times copied or run"}
+  },
+  "required": [
+    "index", "id", "cmd", "created_at", "tags", "note", "source", "repo", "branch", "cwd",
+    "last_peak_rss", "pinned", "uses"
+  ],
+  "additionalProperties": false
+}"#;
+
+fn memo_json(memo: &Memo) -> String {
+    format!(
+        "{{\"index\":{},\"id\":{},\"cmd\":{},\"created_at\":{},\"tags\":[{}],\
+         \"note\":{},\"source\":{},\"repo\":{},\"branch\":{},\"cwd\":{},\
+         \"last_peak_rss\":{},\"pinned\":{},\"uses\":{}}}",
+        memo.index,
+        memo.id,
+        json_string(&memo.cmd),
+        memo.created_at,
+        memo.tag_list()
+            .iter()
+            .map(|t| json_string(t))
+            .collect::<Vec<_>>()
+            .join(","),
+        json_string(&memo.note),
+        json_string(&memo.source),
+        json_string(&memo.repo),
+        json_string(&memo.branch),
+        json_string(&memo.cwd),
+        memo.peak_rss.map_or("null".to_string(), |rss| rss.to_string()),
+        memo.pinned,
+        memo.uses
+    )
+}
+
+/// Where a listing goes: stdout, or the `--out` file (parents created).
+fn open_output(opts: &ListOpts) -> io::Result<Box<dyn Write>> {
+    match &opts.out {
+        Some(path) => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            Ok(Box::new(io::BufWriter::new(fs::File::create(path)?)))
+        }
+        None => Ok(Box::new(io::BufWriter::new(io::stdout().lock()))),
+    }
+}
+
+/// Lists and renders the rows for `opts`. Unbounded listings are streamed
+/// straight from the query so a large database isn't buffered; the `--fields`
+/// table needs every row to size its columns and is always buffered.
+fn print_listing(conn: &Connection, opts: &ListOpts) -> i32 {
+    let bounded = opts.limit != usize::MAX || !opts.fields.is_empty();
+    if bounded && !opts.ndjson {
+        return match list_cmds(conn, opts) {
+            Ok(rows) => print_rows(&rows, opts),
+            Err(err) => {
+                eprintln!("db error: {err}");
+                1
+            }
+        };
+    }
+    let mut out = match open_output(opts) {
+        Ok(out) => out,
+        Err(err) => return write_status(Err(err)),
+    };
+    let now = now_secs();
+    let mut count = 0;
+    let mut write_err = None;
+    let walked = each_cmd(conn, opts, |memo| {
+        let result = if opts.ndjson {
+            // Flush in batches so a reader on the other end of a pipe sees
+            // rows as they come without a write per line.
+            writeln!(out, "{}", memo_json(&memo)).and_then(|()| {
+                if count % NDJSON_FLUSH_EVERY == NDJSON_FLUSH_EVERY - 1 {
+                    out.flush()
+                } else {
+                    Ok(())
+                }
+            })
+        } else if opts.json {
+            let sep = if count == 0 { "[\n" } else { ",\n" };
+            write!(out, "{sep}  {}", memo_json(&memo))
+        } else {
+            writeln!(out, "{}", format_row(&memo, opts, now))
+        };
+        count += 1;
+        match result {
+            Ok(()) => true,
+            Err(err) => {
+                write_err = Some(err);
+                false
+            }
+        }
+    });
+    if let Err(err) = walked {
+        eprintln!("db error: {err}");
+        return 1;
+    }
+    let result = match write_err {
+        Some(err) => Err(err),
+        None => match (opts.ndjson, opts.json, count) {
+            (true, _, _) => Ok(()),
+            (false, true, 0) => writeln!(out, "[]"),
+            (false, true, _) => writeln!(out, "\n]"),
+            (false, false, 0) => writeln!(out, "no entries"),
+            (false, false, _) => Ok(()),
+        }
+        .and_then(|()| out.flush()),
+    };
+    write_status(result)
+}
+
+/// Renders rows to stdout or the `--out` file and returns the exit code.
+fn print_rows(rows: &[Memo], opts: &ListOpts) -> i32 {
+    let result = open_output(opts).and_then(|mut out| {
+        write_rows(&mut out, rows, opts)?;
+        out.flush()
+    });
+    write_status(result)
+}
+
+/// Turns the outcome of writing output into an exit code. A reader that
+/// closes the pipe early (`memo list | head`) is not an error.
+fn write_status(result: io::Result<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => 0,
+        Err(err) => {
+            eprintln!("write error: {err}");
+            1
+        }
+    }
+}
+
+fn write_rows(out: &mut dyn Write, rows: &[Memo], opts: &ListOpts) -> io::Result<()> {
+    if opts.json {
+        let items: Vec<String> = rows.iter().map(|memo| format!("  {}", memo_json(memo))).collect();
+        if items.is_empty() {
+            writeln!(out, "[]")?;
+        } else {
+            writeln!(out, "[\n{}\n]", items.join(",\n"))?;
+        }
+        return Ok(());
+    }
+    if rows.is_empty() {
+        writeln!(out, "no entries")?;
+        return Ok(());
+    }
+    let now = now_secs();
+    if !opts.fields.is_empty() {
+        return write_table(out, rows, opts, now);
+    }
+    for memo in rows {
+        writeln!(out, "{}", format_row(memo, opts, now))?;
+    }
+    Ok(())
+}
+
+/// Writes the `--fields` columns aligned under a header. Columns with a
+/// `--fields-width` are padded or truncated to it; the rest fit their widest
+/// value. The last column is never padded.
+fn write_table(out: &mut dyn Write, rows: &[Memo], opts: &ListOpts, now: i64) -> io::Result<()> {
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|memo| {
+            let cell = |f: &Field| match f {
+                Field::Cwd if opts.relative_paths => display_path(&memo.cwd),
+                _ => f.value(memo, now),
+            };
+            opts.fields.iter().map(cell).collect()
+        })
+        .collect();
+    let widths: Vec<usize> = opts
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(col, field)| {
+            let fixed = opts.field_widths.iter().find(|(f, _)| f == field);
+            match fixed {
+                Some((_, width)) => *width,
+                None => cells
+                    .iter()
+                    .map(|row| row[col].chars().count())
+                    .chain([field.name().len()])
+                    .max()
+                    .unwrap_or(0),
+            }
+        })
+        .collect();
+    let header: Vec<String> = opts.fields.iter().map(|f| f.name().to_uppercase()).collect();
+    for row in std::iter::once(&header).chain(&cells) {
+        let mut line = String::new();
+        for (col, value) in row.iter().enumerate() {
+            let width = widths[col];
+            let value = fit_width(value, width);
+            if col + 1 < row.len() {
+                line.push_str(&format!("{value:<width$}  "));
+            } else {
+                line.push_str(&value);
+            }
+        }
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// Truncates to `width` characters, marking the cut with `…`.
+fn fit_width(value: &str, width: usize) -> String {
+    if value.chars().count() <= width {
+        return value.to_string();
+    }
+    let mut cut: String = value.chars().take(width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
+fn format_row(memo: &Memo, opts: &ListOpts, now: i64) -> String {
+    let pin = if memo.pinned { "*" } else { "" };
+    let mut line = format!("{pin}[{}] ", memo.index);
+    match opts.time {
+        Some(TimeStyle::Relative) => {
+            line.push_str(&format!("({}) ", format_age(memo.created_at, now)));
+        }
+        Some(TimeStyle::Iso) => line.push_str(&format!("({}) ", format_iso(memo.created_at))),
+        None => {}
+    }
+    if let Some(rss) = memo.peak_rss.filter(|_| opts.rss) {
+        line.push_str(&format!("(peak {}) ", format_bytes(rss)));
+    }
+    if opts.show_cwd && !memo.cwd.is_empty() {
+        let cwd = if opts.relative_paths { display_path(&memo.cwd) } else { memo.cwd.clone() };
+        line.push_str(&format!("(in {cwd}) "));
+    }
+    line.push_str(&memo.cmd);
+    let tags = memo.tag_list();
+    if !tags.is_empty() {
+        line.push_str("  #");
+        line.push_str(&tags.join(" #"));
+    }
+    if !memo.note.is_empty() {
+        line.push_str("  // ");
+        line.push_str(&memo.note);
+    }
+    line
+}
+
+/// Adds tags to a memo, keeping existing ones and their order.
+fn add_tags(conn: &Connection, id: i64, new_tags: &[&str]) -> rusqlite::Result<String> {
+    let current: String =
+        conn.query_row("SELECT tags FROM memos WHERE id = ?", params![id], |row| row.get(0))?;
+    let mut tags = split_tags(&current);
+    for tag in new_tags {
+        if !tags.contains(tag) {
+            tags.push(tag);
+        }
+    }
+    let joined = tags.join(",");
+    let _signals = DeferSignals::new();
+    conn.execute("UPDATE memos SET tags = ? WHERE id = ?", params![joined, id])?;
+    Ok(joined)
+}
+
+/// Removes tags from a memo, keeping the rest in order. Re-joining the split
+/// list also drops any empty entries left by stray commas.
+fn remove_tags(conn: &Connection, id: i64, old_tags: &[&str]) -> rusqlite::Result<String> {
+    let current: String =
+        conn.query_row("SELECT tags FROM memos WHERE id = ?", params![id], |row| row.get(0))?;
+    let mut tags = split_tags(&current);
+    tags.retain(|tag| !old_tags.contains(tag));
+    let joined = tags.join(",");
+    let _signals = DeferSignals::new();
+    conn.execute("UPDATE memos SET tags = ? WHERE id = ?", params![joined, id])?;
+    Ok(joined)
+}
+
+/// Deletes every memo whose command equals the next newer memo's, so
+/// back-to-back saves collapse while repeats from different times stay.
+fn prune_adjacent(conn: &mut Connection) -> rusqlite::Result<usize> {
+    let _signals = DeferSignals::new();
+    let tx = conn.transaction()?;
+    let rows = {
+        let mut stmt =
+            tx.prepare("SELECT id, cmd FROM plain_memos ORDER BY created_at DESC, id DESC")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows
+    };
+    let mut removed = 0;
+    for pair in rows.windows(2) {
+        let (newer, older) = (&pair[0], &pair[1]);
+        if newer.1 == older.1 {
+            removed += tx.execute("DELETE FROM memos WHERE id = ?", params![older.0])?;
+        }
+    }
+    tx.commit()?;
+    Ok(removed)
+}
+
+/// `memo edit <N>`: opens the command in `$EDITOR` (else `$VISUAL`, else
+/// `vi`) and stores the result on the same row.
+fn edit_command(conn: &Connection, args: &[String]) -> i32 {
+    let [arg] = args else {
+        usage();
+        return EXIT_USAGE;
+    };
+    let memo = match lookup_memo(conn, arg, false) {
+        Ok(memo) => memo,
+        Err(code) => return code,
+    };
+    let edited = match edit_in_editor(&memo.cmd) {
+        Ok(Some(edited)) => edited,
+        Ok(None) => {
+            outln!("no changes");
+            return 0;
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            return 1;
+        }
+    };
+    // Editors end the file with a newline that the command never had.
+    let cmd = edited.strip_suffix('\n').unwrap_or(&edited);
+    let cmd = cmd.strip_suffix('\r').unwrap_or(cmd);
+    if cmd == memo.cmd {
+        outln!("no changes");
+        return 0;
+    }
+    if cmd.trim().is_empty() {
+        eprintln!("empty command; not changed (use memo delete to remove it)");
+        return 1;
+    }
+    if let Err(err) = update_cmd(conn, memo.id, cmd) {
+        eprintln!("db error: {err}");
+        return 1;
+    }
+    outln!("edited [{}]", memo.index);
+    0
+}
+
+/// Writes `text` to a temporary file, opens it in the editor and reads it
+/// back. None when the editor exits nonzero.
+fn edit_in_editor(text: &str) -> Result<Option<String>, String> {
+    let editor = ["EDITOR", "VISUAL"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+    let dir = state_dir();
+    let (path, mut file) = create_edit_file(&dir)
+        .map_err(|err| format!("cannot create a file in {}: {err}", dir.display()))?;
+    let written = writeln!(file, "{text}");
+    drop(file);
+    if let Err(err) = written {
+        let _ = fs::remove_file(&path);
+        return Err(format!("cannot write {}: {err}", path.display()));
+    }
+    // The editor variable may carry arguments, e.g. `code --wait`.
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+    let status = Command::new(program).args(words).arg(&path).status();
+    let result = match status {
+        Ok(status) if status.success() => fs::read_to_string(&path)
+            .map(Some)
+            .map_err(|err| format!("cannot read {}: {err}", path.display())),
+        Ok(_) => Ok(None),
+        Err(err) => Err(format!("cannot start editor '{program}': {err}")),
+    };
+    let _ = fs::remove_file(&path);
+    result
+}
+
+/// Creates a new `memo-edit-*.sh` file in `dir`, readable only by the user.
+/// create_new refuses an existing name or symlink, so a taken name is
+/// retried with another suffix instead of being written through.
+fn create_edit_file(dir: &Path) -> io::Result<(PathBuf, fs::File)> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+    let mut last_err = None;
+    for attempt in 0..16u32 {
+        let name = format!("memo-edit-{}-{:x}.sh", std::process::id(), nanos ^ attempt);
+        let path = dir.join(name);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => last_err = Some(err),
+            Err(err) => return Err(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::from(io::ErrorKind::AlreadyExists)))
+}
+
+fn update_cmd(conn: &Connection, id: i64, cmd: &str) -> rusqlite::Result<()> {
+    let (cmd, nonce) = seal_cmd(cmd)?;
+    let _signals = DeferSignals::new();
+    conn.execute("UPDATE memos SET cmd = ?, nonce = ? WHERE id = ?", params![cmd, nonce, id])?;
+    Ok(())
+}
+
+fn tag_command(conn: &Connection, args: &[String]) -> i32 {
+    let (idx, tags) = match args.split_first() {
+        Some((n, tags)) if !tags.is_empty() => match n.parse::<usize>() {
+            Ok(idx) => (idx, tags),
+            Err(_) => {
+                usage();
+                return EXIT_USAGE;
+            }
+        },
+        _ => {
+            usage();
+            return EXIT_USAGE;
+        }
+    };
+    let (remove, tags) = match tags.split_first() {
+        Some((flag, rest)) if flag == "--remove" => (true, rest),
+        _ => (false, tags),
+    };
+    let tags: Vec<&str> = tags.iter().flat_map(|t| split_tags(t)).collect();
+    if tags.is_empty() {
+        usage();
+        return EXIT_USAGE;
+    }
+    let id = match id_by_index(conn, idx, false).ok().flatten() {
+        Some(id) => id,
+        None => {
+            eprintln!("not found");
+            return 1;
+        }
+    };
+    let result = if remove {
+        remove_tags(conn, id, &tags)
+    } else {
+        add_tags(conn, id, &tags)
+    };
+    match result {
+        Ok(tags) => {
+            outln!("tagged [{idx}]: {tags}");
+            0
+        }
+        Err(err) => {
+            eprintln!("db error: {err}");
+            1
+        }
+    }
+}
+
+/// Parses an index argument: `N` counts from the newest entry, `^N` from the
+/// oldest. Returns the index and whether it is anchored at the oldest end.
+fn parse_index(arg: &str) -> Option<(usize, bool)> {
+    match arg.strip_prefix('^') {
+        Some(rest) => rest.parse().ok().map(|n| (n, true)),
+        None => arg.parse().ok().map(|n| (n, false)),
+    }
+}
+
+/// Content hash of a command: 64-bit FNV-1a as 16 hex digits. It only needs
+/// to tell a few hundred commands apart, not resist tampering. FNV alone
+/// leaves commands that differ in one late character with the same leading
+/// digits, so the result goes through the murmur3 finalizer to spread it.
+fn cmd_hash(cmd: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in cmd.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+    format!("{hash:016x}")
+}
+
+const SHORT_HASH_LEN: usize = 7;
+/// Shortest hash prefix accepted in place of an index.
+const MIN_HASH_PREFIX: usize = 4;
+
+fn short_hash(cmd: &str) -> String {
+    cmd_hash(cmd)[..SHORT_HASH_LEN].to_string()
+}
+
+/// The hash two characters past a `typed`-character prefix, as a hint when
+/// the prefix is ambiguous; the whole hash once there is no more.
+fn longer_hash(cmd: &str, typed: usize) -> String {
+    let mut hash = cmd_hash(cmd);
+    hash.truncate(typed + 2);
+    hash
+}
+
+/// Finds the commands whose hash starts with `prefix`, newest first, each
+/// once.
+fn memos_by_hash(conn: &Connection, prefix: &str) -> rusqlite::Result<Vec<Memo>> {
+    let mut opts = ListOpts::new(usize::MAX, None);
+    opts.distinct = true;
+    let mut out = Vec::new();
+    each_cmd(conn, &opts, |memo| {
+        if cmd_hash(&memo.cmd).starts_with(prefix) {
+            out.push(memo);
+        }
+        true
+    })?;
+    Ok(out)
+}
+
+fn memo_by_id(conn: &Connection, id: i64) -> rusqlite::Result<Option<Memo>> {
+    let opts = ListOpts::new(usize::MAX, None);
+    let mut found = None;
+    each_cmd(conn, &opts, |memo| {
+        if memo.id != id {
+            return true;
+        }
+        found = Some(memo);
+        false
+    })?;
+    Ok(found)
+}
+
+/// Resolves a `print`/`run` argument: an index (`N`, `^N`) or a hash prefix
+/// of at least MIN_HASH_PREFIX hex digits. All-digit arguments are always
+/// indexes. Errors are reported here; Err holds the exit code.
+fn lookup_memo(conn: &Connection, arg: &str, from_oldest: bool) -> Result<Memo, i32> {
+    if let Some((idx, oldest)) = parse_index(arg) {
+        let found = id_by_index(conn, idx, oldest || from_oldest).and_then(|id| match id {
+            Some(id) => memo_by_id(conn, id),
+            None => Ok(None),
+        });
+        return match found {
+            Ok(Some(memo)) => Ok(memo),
+            Ok(None) => {
+                eprintln!("not found: {arg}");
+                Err(EXIT_NOT_FOUND)
+            }
+            Err(err) => {
+                eprintln!("db error: {err}");
+                Err(1)
+            }
+        };
+    }
+    let prefix = arg.to_ascii_lowercase();
+    let is_hash = (MIN_HASH_PREFIX..=16).contains(&prefix.len())
+        && prefix.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_hash {
+        usage();
+        return Err(EXIT_USAGE);
+    }
+    let mut matches = match memos_by_hash(conn, &prefix) {
+        Ok(matches) => matches,
+        Err(err) => {
+            eprintln!("db error: {err}");
+            return Err(1);
+        }
+    };
+    match matches.len() {
+        0 => {
+            eprintln!("not found: {arg}");
+            Err(EXIT_NOT_FOUND)
+        }
+        1 => Ok(matches.remove(0)),
+        _ => {
+            eprintln!("ambiguous hash {arg}; use more characters:");
+            for memo in matches {
+                eprintln!("{} {}", longer_hash(&memo.cmd, prefix.len()), memo.cmd);
+            }
+            Err(EXIT_AMBIGUOUS)
+        }
+    }
+}
+
+fn cmd_by_index(conn: &Connection, index: usize, oldest: bool) -> rusqlite::Result<Option<String>> {
+    if index < 1 {
+        return Ok(None);
+    }
+    let sql = if oldest {
+        "SELECT cmd FROM plain_memos ORDER BY created_at, id LIMIT 1 OFFSET ?"
+    } else {
+        "SELECT cmd FROM plain_memos ORDER BY created_at DESC, id DESC LIMIT 1 OFFSET ?"
+    };
+    conn.query_row(sql, params![index as i64 - 1], |row| row.get(0))
+        .optional()
+}
+
+fn id_by_index(conn: &Connection, index: usize, oldest: bool) -> rusqlite::Result<Option<i64>> {
+    if index < 1 {
+        return Ok(None);
+    }
+    let sql = if oldest {
+        "SELECT id FROM memos ORDER BY created_at, id LIMIT 1 OFFSET ?"
+    } else {
+        "SELECT id FROM memos ORDER BY created_at DESC, id DESC LIMIT 1 OFFSET ?"
+    };
+    conn.query_row(sql, params![index as i64 - 1], |row| row.get(0))
+        .optional()
+}
+
+/// Deletes the memos at `indexes` in one transaction. Every index is
+/// resolved to its row id before anything is deleted, so `2,5` removes what
+/// the listing showed as [2] and [5]. Err(Some(arg)) names an index that
+/// matched nothing; then nothing is deleted.
+fn delete_indexes(conn: &mut Connection, indexes: &[&str]) -> Result<(), Option<String>> {
+    let db_err = |err: rusqlite::Error| {
+        eprintln!("db error: {err}");
+        None
+    };
+    let _signals = DeferSignals::new();
+    let tx = conn.transaction().map_err(db_err)?;
+    let mut ids = Vec::new();
+    for arg in indexes {
+        let Some((idx, oldest)) = parse_index(arg) else {
+            return Err(Some(arg.to_string()));
+        };
+        match id_by_index(&tx, idx, oldest).map_err(db_err)? {
+            Some(id) if !ids.contains(&id) => ids.push(id),
+            Some(_) => {}
+            None => return Err(Some(arg.to_string())),
+        }
+    }
+    for id in &ids {
+        tx.execute("DELETE FROM memos WHERE id = ?", params![id]).map_err(db_err)?;
+    }
+    tx.commit().map_err(db_err)
+}
+
+/// Gives a row the next id and sets its timestamp, which moves it to the
+/// front of the list unless `created_at` is older than other rows. Returns
+/// the new id.
+fn touch_row(conn: &Connection, id: i64, created_at: i64) -> rusqlite::Result<i64> {
+    // Past every id AUTOINCREMENT has handed out, deleted rows' included.
+    let new_id: i64 = conn.query_row(
+        "SELECT MAX(IFNULL((SELECT MAX(id) FROM memos), 0), \
+         IFNULL((SELECT seq FROM sqlite_sequence WHERE name = 'memos'), 0)) + 1",
+        [],
+        |row| row.get(0),
+    )?;
+    conn.execute(
+        "UPDATE memos SET id = ?, created_at = ? WHERE id = ?",
+        params![new_id, created_at, id],
+    )?;
+    // Sequences refer to memos by id, so they follow the move.
+    conn.execute(
+        "UPDATE sequences SET memo_id = ? WHERE memo_id = ?",
+        params![new_id, id],
+    )?;
+    // Keep AUTOINCREMENT's counter at or past the new id, so the id is never
+    // handed out again after this row is deleted.
+    conn.execute(
+        "UPDATE sqlite_sequence SET seq = MAX(seq, ?) WHERE name = 'memos'",
+        params![new_id],
+    )?;
+    Ok(new_id)
+}
+
+fn resolve_ref(conn: &Connection, reference: &str) -> Result<String, i32> {
+    if let Ok(idx) = reference.parse::<usize>() {
+        match cmd_by_index(conn, idx, false) {
+            Ok(Some(cmd)) => return Ok(cmd),
+            Ok(None) => {}
+            Err(err) => {
+                eprintln!("db error: {err}");
+                return Err(1);
+            }
+        }
+    }
+    let mut opts = ListOpts::new(usize::MAX, Some(reference));
+    opts.distinct = true;
+    let mut matches = match list_cmds(conn, &opts) {
+        Ok(matches) => matches,
+        Err(err) => {
+            eprintln!("db error: {err}");
+            return Err(1);
+        }
+    };
+    match matches.len() {
+        0 => Err(EXIT_UNRESOLVED),
+        1 => Ok(matches.remove(0).cmd),
+        _ => {
+            eprintln!("ambiguous: {} commands match", matches.len());
+            for memo in matches {
+                eprintln!("[{}] {}", memo.index, memo.cmd);
+            }
+            Err(EXIT_AMBIGUOUS)
+        }
+    }
+}
+
+/// History files to consider, in priority order: `$MEMO_HISTFILES`
+/// (colon-separated), then `$HISTFILE`, then the shell's default history
+/// file (`~/.zsh_history` when the shell is unknown).
+fn history_files(shell: Option<ShellKind>) -> Vec<PathBuf> {
+    if let Some(list) = env::var_os("MEMO_HISTFILES").filter(|v| !v.is_empty()) {
+        return env::split_paths(&list)
+            .map(|p| expand_home(&p.to_string_lossy()))
+            .collect();
+    }
+    let histfile = env::var("HISTFILE")
+        .ok()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| shell.unwrap_or(ShellKind::Zsh).default_history_file());
+    vec![histfile]
+}
+
+fn read_history(path: &Path) -> Option<String> {
+    read_history_from(fs::File::open(path).ok()?, path)
+}
+
+/// Reads a whole history file, decompressing it in the same pass when it is
+/// gzip (a rotated `~/.zsh_history.gz`).
+fn read_history_from(file: fs::File, path: &Path) -> Option<String> {
+    let mut reader = io::BufReader::new(file);
+    let buf = if reader.fill_buf().ok()?.starts_with(&gzip::MAGIC) {
+        match gzip::decompress(reader) {
+            Ok(buf) => buf,
+            Err(err) => {
+                eprintln!("warning: cannot read {}: {err}", path.display());
+                return None;
+            }
+        }
+    } else {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).ok()?;
+        buf
+    };
+    Some(String::from_utf8_lossy(&buf).into_owned())
+}
+
+struct HistoryEntry {
+    cmd: String,
+    timestamp: Option<i64>,
+}
+
+/// Splits the zsh extended history prefix (`: <start>:<elapsed>;`) from a
+/// line, returning the start time when there is one.
+fn parse_history_line(line: &str) -> (Option<i64>, &str) {
+    if let Some(rest) = line.strip_prefix(':') {
+        if let Some((meta, after)) = rest.split_once(';') {
+            let start = meta
+                .trim()
+                .split(':')
+                .next()
+                .and_then(|ts| ts.trim().parse::<i64>().ok());
+            return (start, after.trim());
+        }
+    }
+    (None, line.trim())
+}
+
+/// Commands that run the rest of the line, with their options that take the
+/// next word as a value (`sudo -u root memo`).
+const WRAPPERS: &[(&str, &[&str])] = &[
+    ("sudo", &["-u", "-g", "-C", "-D", "-p", "-U", "--user", "--group", "--chdir", "--prompt"]),
+    ("env", &["-u", "-C", "--unset", "--chdir"]),
+    ("nice", &["-n", "--adjustment"]),
+    ("timeout", &["-s", "-k", "--signal", "--kill-after"]),
+    ("exec", &["-a"]),
+    ("command", &[]),
+    ("builtin", &[]),
+    ("nohup", &[]),
+    ("time", &[]),
+];
+
+/// True when a line runs memo itself: `memo ...`, `/usr/bin/memo`, `sudo memo`,
+/// `FOO=1 memo`, or any name listed in MEMO_ALIASES (comma or space separated,
+/// e.g. `m,mm`).
+fn is_memo_invocation(cmd: &str) -> bool {
+    let is_assignment = |word: &str| {
+        word.split_once('=').is_some_and(|(name, _)| {
+            !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        })
+    };
+    let mut words = cmd.split_whitespace();
+    let mut value_opts: &[&str] = &[];
+    // timeout's duration comes before the command it runs.
+    let mut wants_duration = false;
+    let word = loop {
+        let Some(word) = words.next() else {
+            return false;
+        };
+        if let Some((name, opts)) = WRAPPERS.iter().find(|(name, _)| *name == word) {
+            value_opts = opts;
+            wants_duration = *name == "timeout";
+            continue;
+        }
+        if word.starts_with('-') {
+            if value_opts.contains(&word) {
+                words.next();
+            }
+            continue;
+        }
+        if is_assignment(word) || std::mem::take(&mut wants_duration) {
+            continue;
+        }
+        break word;
+    };
+    let word = word.trim_start_matches('\\');
+    let name = word.rsplit('/').next().unwrap_or(word);
+    if name == "memo" {
+        return true;
+    }
+    let aliases = env::var("MEMO_ALIASES").unwrap_or_default();
+    aliases
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .any(|alias| !alias.is_empty() && alias == word)
+}
+
+/// Shell history file formats memo can parse.
+#[derive(Clone, Copy, PartialEq)]
+enum ShellKind {
+    /// `: <start>:<elapsed>;<cmd>` extended history, or plain lines.
+    Zsh,
+    /// Plain lines, optionally preceded by `#<unix time>` comment lines.
+    Bash,
+    /// YAML-ish `- cmd: <cmd>` entries followed by `  when: <unix time>`.
+    Fish,
+    /// PSReadLine's `ConsoleHost_history.txt`: plain lines, with a trailing
+    /// backtick continuing a command onto the next line.
+    Pwsh,
+}
+
+/// File name PSReadLine writes PowerShell history to.
+const PWSH_HISTORY_NAME: &str = "ConsoleHost_history.txt";
+
+impl ShellKind {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "zsh" => Some(ShellKind::Zsh),
+            "bash" | "sh" => Some(ShellKind::Bash),
+            "fish" => Some(ShellKind::Fish),
+            "pwsh" | "powershell" => Some(ShellKind::Pwsh),
+            _ => None,
+        }
+    }
+
+    /// The user's shell: `$MEMO_SHELL` if it names one memo knows, else the
+    /// basename of `$SHELL`.
+    fn active() -> Option<Self> {
+        let by_name = |var: &str| {
+            let shell = env::var_os(var)?;
+            let name = Path::new(&shell).file_name()?.to_string_lossy().into_owned();
+            ShellKind::from_name(&name)
+        };
+        by_name("MEMO_SHELL").or_else(|| by_name("SHELL"))
+    }
+
+    /// Where the shell writes its history when `$HISTFILE` isn't set.
+    fn default_history_file(self) -> PathBuf {
+        match self {
+            ShellKind::Zsh => expand_home("~/.zsh_history"),
+            ShellKind::Bash => expand_home("~/.bash_history"),
+            ShellKind::Fish => xdg_dir("XDG_DATA_HOME", "~/.local/share").join("fish/fish_history"),
+            ShellKind::Pwsh => expand_home(&format!(
+                "~/.local/share/powershell/PSReadLine/{PWSH_HISTORY_NAME}"
+            )),
+        }
+    }
+
+    /// Recognises the shells' default history file names.
+    fn for_file_name(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        match name.strip_suffix(".gz").unwrap_or(name) {
+            ".zsh_history" => Some(ShellKind::Zsh),
+            ".bash_history" => Some(ShellKind::Bash),
+            "fish_history" => Some(ShellKind::Fish),
+            PWSH_HISTORY_NAME => Some(ShellKind::Pwsh),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format of a history file, by name and then by contents.
+    fn for_file(path: &Path, content: &str) -> Self {
+        ShellKind::for_file_name(path).unwrap_or_else(|| ShellKind::detect(content))
+    }
+
+    /// Guesses the format from the file's contents. Plain lines parse the
+    /// same either way, so anything unrecognised is treated as bash.
+    fn detect(content: &str) -> Self {
+        for line in content.lines().take(200) {
+            if line.starts_with("- cmd: ") {
+                return ShellKind::Fish;
+            }
+            if line.starts_with(": ") && parse_history_line(line).0.is_some() {
+                return ShellKind::Zsh;
+            }
+        }
+        ShellKind::Bash
+    }
+
+    /// Parses a whole history file into entries, oldest first, skipping
+    /// memo's own invocations.
+    fn parse(self, content: &str) -> Vec<HistoryEntry> {
+        let mut entries = self.events(content);
+        entries.retain(|e| !is_memo_invocation(&e.cmd));
+        entries
+    }
+
+    /// Every entry in a history file, oldest first, memo's own invocations
+    /// included: the shell's event numbers count those too.
+    fn events(self, content: &str) -> Vec<HistoryEntry> {
+        let mut entries = Vec::new();
+        match self {
+            ShellKind::Zsh => {
+                for line in content.lines() {
+                    let (timestamp, cmd) = parse_history_line(line);
+                    if !cmd.is_empty() {
+                        entries.push(HistoryEntry {
+                            cmd: cmd.to_string(),
+                            timestamp,
+                        });
+                    }
+                }
+            }
+            ShellKind::Bash => {
+                let mut timestamp = None;
+                for line in content.lines() {
+                    if let Some(ts) = bash_timestamp(line) {
+                        timestamp = Some(ts);
+                        continue;
+                    }
+                    let cmd = line.trim();
+                    if !cmd.is_empty() {
+                        entries.push(HistoryEntry {
+                            cmd: cmd.to_string(),
+                            timestamp: timestamp.take(),
+                        });
+                    }
+                }
+            }
+            ShellKind::Fish => {
+                let mut current: Option<HistoryEntry> = None;
+                for line in content.lines() {
+                    if let Some(cmd) = line.strip_prefix("- cmd: ") {
+                        entries.extend(current.take());
+                        current = Some(HistoryEntry {
+                            cmd: unescape_fish(cmd),
+                            timestamp: None,
+                        });
+                    } else if let Some(when) = line.trim_start().strip_prefix("when: ") {
+                        if let Some(entry) = current.as_mut() {
+                            entry.timestamp = when.trim().parse().ok();
+                        }
+                    }
+                }
+                entries.extend(current);
+                entries.retain(|e| !e.cmd.trim().is_empty());
+            }
+            ShellKind::Pwsh => {
+                let mut pending = String::new();
+                for line in content.lines() {
+                    if let Some(part) = line.strip_suffix('`') {
+                        pending.push_str(part);
+                        pending.push('\n');
+                        continue;
+                    }
+                    pending.push_str(line);
+                    let cmd = std::mem::take(&mut pending);
+                    let cmd = cmd.trim();
+                    if !cmd.is_empty() {
+                        entries.push(HistoryEntry {
+                            cmd: cmd.to_string(),
+                            timestamp: None,
+                        });
+                    }
+                }
+            }
+        }
+        entries
+    }
+}
+
+/// Undoes fish's history escaping: `\n` is a newline and `\\` a backslash.
+fn unescape_fish(cmd: &str) -> String {
+    let mut out = String::with_capacity(cmd.len());
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Looks up history event `event` (1-based) by counting entries in the first
+/// readable history file.
+fn read_history_event(event: usize, shell: Option<ShellKind>) -> Result<HistoryEntry, String> {
+    let files = history_files(shell.or_else(ShellKind::active));
+    let Some((path, content)) = files
+        .iter()
+        .find_map(|path| read_history(path).map(|c| (path, c)))
+    else {
+        return Err("no history file found".to_string());
+    };
+    // Events are counted in the file's own format, so fish's `when:` lines
+    // and PowerShell's continuation lines aren't taken for commands.
+    let kind = ShellKind::for_file_name(path)
+        .or(shell)
+        .or_else(ShellKind::active)
+        .unwrap_or_else(|| ShellKind::detect(&content));
+    kind.events(&content)
+        .into_iter()
+        .nth(event.wrapping_sub(1))
+        .ok_or_else(|| format!("history event {event} not found in {}", path.display()))
+}
+
+/// The time in a bash `HISTTIMEFORMAT` comment line (`#1700000000`).
+fn bash_timestamp(line: &str) -> Option<i64> {
+    let digits = line.strip_prefix('#')?.trim_end();
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// The newest command in the history, read in the format of `shell` (by
+/// default the one `$MEMO_SHELL` or `$SHELL` names). Files with a shell's default name are
+/// always read in that shell's format.
+fn read_last_history_command(shell: Option<ShellKind>) -> Option<HistoryEntry> {
+    let shell = shell.or_else(ShellKind::active);
+    history_files(shell)
+        .iter()
+        .find_map(|path| read_last_from(path, ShellKind::for_file_name(path).or(shell)))
+}
+
+/// Where we stopped reading a history file last time, and the last command
+/// seen up to that point.
+struct HistoryCache {
+    inode: u64,
+    offset: u64,
+    last: Option<HistoryEntry>,
+}
+
+fn history_cache_path(histfile: &Path) -> PathBuf {
+    let name = histfile.to_string_lossy().replace(['/', '\\'], "%");
+    cache_dir().join(format!("{name}.offset"))
+}
+
+fn file_inode(meta: &fs::Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        meta.ino()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = meta;
+        0
+    }
+}
+
+// Format: "<inode> <offset> <timestamp or ->" then the command on line two.
+fn load_history_cache(path: &Path) -> Option<HistoryCache> {
+    let content = fs::read_to_string(path).ok()?;
+    let (header, cmd) = content.split_once('\n').unwrap_or((&content, ""));
+    let mut fields = header.split(' ');
+    let inode = fields.next()?.parse().ok()?;
+    let offset = fields.next()?.parse().ok()?;
+    let timestamp = fields.next()?.parse().ok();
+    let last = (!cmd.is_empty()).then(|| HistoryEntry {
+        cmd: cmd.to_string(),
+        timestamp,
+    });
+    Some(HistoryCache { inode, offset, last })
+}
+
+fn store_history_cache(path: &Path, cache: &HistoryCache) {
+    let (timestamp, cmd) = match &cache.last {
+        Some(entry) => (
+            entry.timestamp.map_or("-".to_string(), |ts| ts.to_string()),
+            entry.cmd.as_str(),
+        ),
+        None => ("-".to_string(), ""),
+    };
+    let _ = fs::write(
+        path,
+        format!("{} {} {timestamp}\n{cmd}", cache.inode, cache.offset),
+    );
+}
+
+/// Finds the last command in one history file, parsed as `kind` or, when
+/// that is unknown, in the format its contents suggest. Plain files only
+/// read what was appended since the previous call, using an offset cached
+/// under `$XDG_CACHE_HOME/memo`; a new inode or a shrunken file (rotation)
+/// starts over from the beginning.
+fn read_last_from(histfile: &Path, kind: Option<ShellKind>) -> Option<HistoryEntry> {
+    let last_in = |content: &str| {
+        kind.unwrap_or_else(|| ShellKind::detect(content))
+            .parse(content)
+            .pop()
+    };
+    let mut file = fs::File::open(histfile).ok()?;
+    let meta = file.metadata().ok()?;
+    let mut magic = [0u8; 2];
+    let gzipped = file.read_exact(&mut magic).is_ok() && magic == gzip::MAGIC;
+    // Continuation lines make the tail of a PowerShell history ambiguous
+    // without what came before, so it is always parsed whole.
+    if gzipped || kind == Some(ShellKind::Pwsh) {
+        file.rewind().ok()?;
+        return last_in(&read_history_from(file, histfile)?);
+    }
+
+    let cache_path = history_cache_path(histfile);
+    let inode = file_inode(&meta);
+    let len = meta.len();
+    let cached = load_history_cache(&cache_path).filter(|c| c.inode == inode && c.offset <= len);
+    let (offset, previous) = match cached {
+        Some(cache) => (cache.offset, cache.last),
+        None => (0, None),
+    };
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).ok()?;
+    let last = last_in(&String::from_utf8_lossy(&buf)).or(previous);
+
+    let cache = HistoryCache {
+        inode,
+        offset: offset + buf.len() as u64,
+        last,
+    };
+    store_history_cache(&cache_path, &cache);
+    cache.last
+}
+
+/// Searches the history files themselves, newest first within each file,
+/// labelling each hit with the file it came from.
+fn search_history_files(opts: &ListOpts) -> Vec<(PathBuf, String)> {
+    let mut out = Vec::new();
+    let shell = ShellKind::active();
+    for path in history_files(shell) {
+        let Some(content) = read_history(&path) else {
+            continue;
+        };
+        let kind = ShellKind::for_file_name(&path)
+            .or(shell)
+            .unwrap_or_else(|| ShellKind::detect(&content));
+        for entry in kind.parse(&content).into_iter().rev() {
+            if out.len() >= opts.limit {
+                return out;
+            }
+            if opts.query_matches(&entry.cmd) {
+                out.push((path.clone(), entry.cmd));
+            }
+        }
+    }
+    out
+}
+
+/// Expands a leading `~` or `~/` to `$HOME`. `~user` forms, and any path
+/// when HOME is unset, are returned unchanged rather than guessed at.
+fn expand_home(path: &str) -> PathBuf {
+    match env::var_os("HOME").filter(|h| !h.is_empty()) {
+        Some(home) => expand_home_with(path, Path::new(&home)),
+        None => PathBuf::from(path),
+    }
+}
+
+/// expand_home with the home directory given.
+fn expand_home_with(path: &str, home: &Path) -> PathBuf {
+    match path.strip_prefix('~') {
+        Some("") => home.to_path_buf(),
+        Some(rest) if rest.starts_with('/') => home.join(rest.trim_start_matches('/')),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Shortens a stored path for `--relative-paths`: relative to the current
+/// directory when inside it (`.`, `src/bin`), else with the home directory
+/// written as `~`. Other paths, and empty ones, are shown as stored.
+fn display_path(path: &str) -> String {
+    let path = Path::new(path);
+    let cwd = env::current_dir().ok();
+    if let Some(rest) = cwd.as_deref().and_then(|cwd| path.strip_prefix(cwd).ok()) {
+        if rest.as_os_str().is_empty() {
+            return ".".to_string();
+        }
+        return rest.to_string_lossy().into_owned();
+    }
+    let home = env::var_os("HOME").filter(|h| !h.is_empty()).map(PathBuf::from);
+    match home.as_deref().and_then(|home| path.strip_prefix(home).ok()) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Some(rest) => format!("~/{}", rest.to_string_lossy()),
+        None => path.to_string_lossy().into_owned(),
+    }
+}
+
+/// Whether a command needs the y/N prompt: a built-in pattern or one from
+/// `~/.config/memo/dangerous` matches.
+fn is_dangerous(cmd: &str) -> bool {
+    let patterns = [
+        r"\brm\b",
+        r"\bsudo\b",
+        r"\bdd\b",
+        r"\bmkfs",
+        r"\bshutdown\b",
+        r"\breboot\b",
+        r"\bpoweroff\b",
+        r"\|\s*sh\b",
+    ];
+    patterns.iter().any(|pat| {
+        Regex::new(pat)
+            .map(|re| re.is_match(cmd))
+            .unwrap_or(false)
+    }) || extra_dangerous_patterns().iter().any(|re| re.is_match(cmd))
+}
+
+/// The user's extra dangerous patterns, read once. Unlike the `ignore` and
+/// `critical` files, a bad line only loses that pattern: it is skipped with
+/// a warning so the rest still guard.
+fn extra_dangerous_patterns() -> &'static [Regex] {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        let path = config_dir().join("dangerous");
+        let content = fs::read_to_string(&path).unwrap_or_default();
+        pattern_lines(&content)
+            .filter_map(|line| match Regex::new(line) {
+                Ok(re) => Some(re),
+                Err(err) => {
+                    eprintln!("warning: {}: skipping bad pattern {line}: {err}", path.display());
+                    None
+                }
+            })
+            .collect()
+    })
+}
+
+#[derive(Clone, Copy)]
+enum SecretsMode {
+    Warn,
+    Skip,
+    Mask,
+}
+
+fn secrets_mode() -> Option<SecretsMode> {
+    let value = env::var("MEMO_SECRETS").ok()?;
+    match value.as_str() {
+        "warn" => Some(SecretsMode::Warn),
+        "skip" => Some(SecretsMode::Skip),
+        "mask" => Some(SecretsMode::Mask),
+        "" => None,
+        other => {
+            eprintln!("warning: ignoring MEMO_SECRETS={other} (expected warn, skip or mask)");
+            None
+        }
+    }
+}
+
+// The `secret` group, when present, is the part that gets masked; otherwise
+// the whole match is.
+const SECRET_PATTERNS: &[&str] = &[
+    r"\bAKIA[0-9A-Z]{16}\b",
+    r"\b(?:ghp|gho|ghu|ghs|ghr)_[A-Za-z0-9]{36}\b",
+    r"\bgithub_pat_[A-Za-z0-9_]{20,}",
+    r"\bxox[abprs]-[A-Za-z0-9-]{10,}",
+    r"\bsk-[A-Za-z0-9_-]{20,}",
+    r"(?i)\bbearer\s+(?P<secret>[A-Za-z0-9._~+/=-]{8,})",
+    r"(?i)(?:password|passwd|secret|token|api[_-]?key)[A-Z0-9_]*=(?P<secret>[^\s'\x22]+)",
+    r"(?i)--(?:password|token|secret|api-key)[= ](?P<secret>[^\s'\x22]+)",
+];
+
+fn secret_regexes() -> Vec<Regex> {
+    SECRET_PATTERNS
+        .iter()
+        .filter_map(|pat| Regex::new(pat).ok())
+        .collect()
+}
+
+fn has_secret(cmd: &str) -> bool {
+    secret_regexes().iter().any(|re| re.is_match(cmd))
+}
+
+fn mask_secrets(cmd: &str) -> String {
+    let mut out = cmd.to_string();
+    for re in secret_regexes() {
+        out = re
+            .replace_all(&out, |caps: &regex::Captures| {
+                let whole = caps.get(0).map(|m| m.as_str()).unwrap_or_default();
+                match caps.name("secret") {
+                    Some(secret) => whole.replacen(secret.as_str(), "****", 1),
+                    None => "****".to_string(),
+                }
+            })
+            .into_owned();
+    }
+    out
+}
+
+/// Commands bad enough that `y` is too easy to type by reflex. The
+/// `critical` file in the config directory replaces this list.
+const CRITICAL_PATTERNS: &[&str] = &[
+    r"\brm\s+(-\S+\s+)*(/|/\*|~|~/|\$HOME)(\s|$)",
+    r"\bmkfs",
+    r"\bdd\b.*\bof=/dev/",
+    r":\(\)\s*\{.*:\s*\|\s*:",
+];
+
+fn is_critical(cmd: &str) -> Result<bool, String> {
+    let patterns = match load_patterns("critical")? {
+        Some(patterns) => patterns,
+        None => CRITICAL_PATTERNS.iter().filter_map(|pat| Regex::new(pat).ok()).collect(),
+    };
+    Ok(patterns.iter().any(|re| re.is_match(cmd)))
+}
+
+/// The stronger prompt for critical commands: the user has to type `word`
+/// (the command's program unless `--confirm-word` says otherwise).
+fn confirm_word(word: &str) -> bool {
+    print!("critical command, type '{word}' to run: ");
+    let _ = io::stdout().flush();
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input).is_err() {
+        return false;
+    }
+    input.trim() == word
+}
+
+fn confirm_run() -> bool {
+    confirm("dangerous command, run?")
+}
+
+/// Asks a yes/no question on the terminal; anything but y/yes is no.
+fn confirm(question: &str) -> bool {
+    print!("{question} [y/N] ");
+    let _ = io::stdout().flush();
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input).is_err() {
+        return false;
+    }
+    matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn which(cmd: &str) -> Option<PathBuf> {
+    let paths = env::var_os("PATH")?;
+    for path in env::split_paths(&paths) {
+        let candidate = path.join(cmd);
+        if candidate.exists() {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+fn clipboard_command() -> Option<Vec<String>> {
+    if cfg!(target_os = "macos") {
+        if which("pbcopy").is_some() {
+            return Some(vec!["pbcopy".to_string()]);
+        }
+        return None;
+    }
+    if which("wl-copy").is_some() {
+        return Some(vec!["wl-copy".to_string()]);
+    }
+    if which("xclip").is_some() {
+        return Some(vec!["xclip".to_string(), "-selection".to_string(), "clipboard".to_string()]);
+    }
+    if which("xsel").is_some() {
+        return Some(vec![
+            "xsel".to_string(),
+            "--clipboard".to_string(),
+            "--input".to_string(),
+        ]);
+    }
+    None
+}
+
+/// Copies with the local clipboard tool, falling back to OSC 52 when there
+/// is none or it fails (e.g. over SSH). `MEMO_CLIPBOARD=osc52` skips the tool.
+fn copy_to_clipboard(text: &str) -> bool {
+    let osc52_only = env::var("MEMO_CLIPBOARD").is_ok_and(|v| v == "osc52");
+    let tool = clipboard_command().filter(|_| !osc52_only);
+    tool.is_some_and(|cmd| copy_with_tool(&cmd, text)) || copy_with_osc52(text)
+}
+
+fn copy_with_tool(cmd: &[String], text: &str) -> bool {
+    let mut child = match Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(_) => return false,
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        if stdin.write_all(text.as_bytes()).is_err() {
+            return false;
+        }
+    }
+    child.wait().map(|s| s.success()).unwrap_or(false)
+}
+
+/// Asks the terminal to set the clipboard with the OSC 52 escape, which
+/// reaches the local terminal through SSH. Only when stdout is a terminal;
+/// whether the copy happens is up to the terminal.
+fn copy_with_osc52(text: &str) -> bool {
+    let mut stdout = io::stdout();
+    if !stdout.is_terminal() {
+        return false;
+    }
+    let sequence = format!("\x1b]52;c;{}\x07", base64(text.as_bytes()));
+    stdout.write_all(sequence.as_bytes()).and_then(|()| stdout.flush()).is_ok()
+}
+
+/// Standard base64 with padding.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let byte = |i: usize| u32::from(chunk.get(i).copied().unwrap_or(0));
+        let n = (byte(0) << 16) | (byte(1) << 8) | byte(2);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Sets the terminal title with the OSC 0 escape, only when stdout is a
+/// terminal. Control characters are dropped so the command can't end the
+/// sequence early.
+fn set_terminal_title(title: &str) {
+    let mut stdout = io::stdout();
+    if !stdout.is_terminal() {
+        return;
+    }
+    let title: String = title.chars().filter(|c| !c.is_control()).collect();
+    let _ = write!(stdout, "\x1b]0;{title}\x07");
+    let _ = stdout.flush();
+}
+
+/// Drops a trailing shell comment. Like the shell, `#` only starts a comment
+/// at the beginning of a word and outside quotes, so `http://host/#frag` and
+/// `echo "#1"` are left alone.
+fn strip_trailing_comment(cmd: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut prev_blank = true;
+    for (i, c) in cmd.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            } else if c == '\\' && q == '"' {
+                escaped = true;
+            }
+        } else {
+            match c {
+                '\\' => escaped = true,
+                '\'' | '"' => quote = Some(c),
+                '#' if prev_blank => return cmd[..i].trim_end(),
+                _ => {}
+            }
+        }
+        prev_blank = c.is_whitespace();
+    }
+    cmd
+}
+
+/// Drops a trailing background `&` so a replay runs in the foreground.
+/// `&&`, `|&`, `>&` and an escaped `\&` are not background operators.
+fn trim_trailing_amp(cmd: &str) -> &str {
+    let trimmed = cmd.trim_end();
+    match trimmed.strip_suffix('&') {
+        Some(rest) if !rest.ends_with(['&', '|', '>', '\\']) => rest.trim_end(),
+        _ => cmd,
+    }
+}
+
+fn save_command(conn: &Connection, args: &[String]) -> i32 {
+    let mut save = SaveOpts::new("manual");
+    let mut if_changed_dir = false;
+    let mut verbose = false;
+    let mut source = None;
+    let mut use_now = false;
+    let mut strip_comments = false;
+    let mut replace_last = false;
+    let mut event = None;
+    let mut shell = None;
+    let mut rest = args;
+    while let Some(flag) = rest.first() {
+        match flag.as_str() {
+            "--detect-secrets-only-warn" => save.secrets = Some(SecretsMode::Warn),
+            "--now" => use_now = true,
+            "--if-changed-dir" => if_changed_dir = true,
+            "--verbose" => verbose = true,
+            "--git-context" => {
+                if save.git.is_none() {
+                    save.git = git_context();
+                }
+            }
+            "--strip-comments" => strip_comments = true,
+            "--trim-trailing-amp" => save.trim_amp = true,
+            "--dedup" => save.dedup = true,
+            "--replace-last" => replace_last = true,
+            "--event" => {
+                let value = rest.get(1).map(|v| v.trim_start_matches('!'));
+                match value.and_then(|v| v.parse::<usize>().ok()).filter(|n| *n > 0) {
+                    Some(n) => event = Some(n),
+                    None => {
+                        eprintln!("invalid --event: {}", value.unwrap_or_default());
+                        return EXIT_USAGE;
+                    }
+                }
+                rest = &rest[1..];
+            }
+            "--shell" => {
+                match rest.get(1).map(|name| (name, ShellKind::from_name(name))) {
+                    Some((_, Some(kind))) => shell = Some(kind),
+                    Some((name, None)) => {
+                        eprintln!("unknown shell: {name} (expected zsh, bash, fish or pwsh)");
+                        return EXIT_USAGE;
+                    }
+                    None => {
+                        eprintln!("missing value for --shell");
+                        return EXIT_USAGE;
+                    }
+                }
+                rest = &rest[1..];
+            }
+            "--source" => {
+                match rest.get(1).filter(|v| !v.trim().is_empty()) {
+                    Some(label) => source = Some(label.trim().to_string()),
+                    None => {
+                        eprintln!("missing value for --source");
+                        return EXIT_USAGE;
+                    }
+                }
+                rest = &rest[1..];
+            }
+            "--" => {
+                rest = &rest[1..];
+                break;
+            }
+            _ => break,
+        }
+        rest = &rest[1..];
+    }
+
+    let explicit = !rest.is_empty() || event.is_some();
+    save.source = match source {
+        Some(source) => source,
+        None if rest.is_empty() => "history".to_string(),
+        None => "manual".to_string(),
+    };
+    let mut cmd = if let Some(event) = event {
+        if !rest.is_empty() {
+            usage();
+            return EXIT_USAGE;
+        }
+        match read_history_event(event, shell) {
+            Ok(entry) => {
+                save.created_at = entry.timestamp;
+                entry.cmd
+            }
+            Err(err) => {
+                eprintln!("{err}");
+                return 1;
+            }
+        }
+    } else if explicit {
+        rest.join(" ")
+    } else {
+        if !auto_save_allowed(conn) {
+            outln!("skipped: saved too recently");
+            return 0;
+        }
+        match read_last_history_command(shell) {
+            Some(entry) => {
+                save.created_at = entry.timestamp;
+                entry.cmd
+            }
+            None => {
+                outln!("no history command found");
+                return 0;
+            }
+        }
+    };
+    if use_now {
+        save.created_at = None;
+    }
+    if strip_comments {
+        cmd = strip_trailing_comment(&cmd).to_string();
+    }
+    if save.trim_amp {
+        cmd = trim_trailing_amp(&cmd).to_string();
+    }
+    if (strip_comments || save.trim_amp) && cmd.is_empty() {
+        outln!("nothing to save");
+        return 0;
+    }
+    if if_changed_dir && last_saved_cwd(conn).ok().flatten().as_deref() == Some(&save.cwd) {
+        if verbose {
+            outln!("skipped: same directory as the last memo ({})", save.cwd);
+        }
+        return 0;
+    }
+    if replace_last {
+        if last_saved_cmd(conn).ok().flatten().is_none() {
+            eprintln!("no entries to replace");
+            return 1;
+        }
+        match replace_last_cmd(conn, &cmd, &save) {
+            Ok(true) => outln!("replaced [1]"),
+            Ok(false) => {}
+            Err(err) => {
+                eprintln!("db error: {err}");
+                return 1;
+            }
+        }
+        return 0;
+    }
+    if !explicit && last_saved_cmd(conn).ok().flatten().as_deref() == Some(&cmd) {
+        outln!("saved");
+        return 0;
+    }
+    if let Ok(true) = insert_cmd(conn, &cmd, &save) {
+        outln!("saved");
+    }
+    0
+}
+
+fn import_command(conn: &mut Connection, args: &[String]) -> i32 {
+    let mut file = None;
+    let mut shell = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--from-shell-history" => file = iter.next().map(|f| expand_home(f)),
+            "--shell" => match iter.next().map(|name| (name, ShellKind::from_name(name))) {
+                Some((_, Some(kind))) => shell = Some(kind),
+                Some((name, None)) => {
+                    eprintln!("unknown shell: {name} (expected zsh, bash, fish or pwsh)");
+                    return EXIT_USAGE;
+                }
+                None => {
+                    eprintln!("missing value for --shell");
+                    return EXIT_USAGE;
+                }
+            },
+            _ => {
+                usage();
+                return EXIT_USAGE;
+            }
+        }
+    }
+    let Some(path) = file else {
+        usage();
+        return EXIT_USAGE;
+    };
+    let Some(content) = read_history(&path) else {
+        eprintln!("cannot read {}", path.display());
+        return 1;
+    };
+    let kind = shell.unwrap_or_else(|| ShellKind::for_file(&path, &content));
+    match import_history(conn, kind.parse(&content)) {
+        Ok((added, skipped)) => {
+            outln!("imported: {added} added, {skipped} duplicates skipped");
+            0
+        }
+        Err(err) => {
+            eprintln!("db error: {err}");
+            1
+        }
+    }
+}
+
+/// `memo pick`: choose one or more commands, with fzf when installed or by
+/// typing numbers otherwise, then copy them (default) or run them in order.
+fn pick_command(conn: &Connection, args: &[String]) -> i32 {
+    let mut run = false;
+    let mut run_opts = RunOpts::default();
+    let mut rest = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--run" => run = true,
+            "--copy" => run = false,
+            "--keep-going" => run_opts.keep_going = true,
+            _ => rest.push(arg.clone()),
+        }
+    }
+    let opts = match parse_list_opts(&rest, usize::MAX) {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("{err}");
+            return EXIT_USAGE;
+        }
+    };
+    let rows = match list_cmds(conn, &opts) {
+        Ok(rows) => rows,
+        Err(err) => {
+            eprintln!("db error: {err}");
+            return 1;
+        }
+    };
+    if rows.is_empty() {
+        eprintln!("no entries");
+        return 1;
+    }
+    let picked = match which("fzf") {
+        Some(_) => pick_with_fzf(&rows),
+        None => pick_by_number(&rows, &opts),
+    };
+    let mut cmds = Vec::new();
+    for idx in picked {
+        let memo = match rows.iter().find(|memo| memo.index == idx) {
+            Some(memo) => Some((memo.cmd.clone(), memo.cwd.clone())),
+            None => id_by_index(conn, idx, false)
+                .ok()
+                .flatten()
+                .and_then(|id| memo_by_id(conn, id).ok().flatten())
+                .map(|memo| (memo.cmd, memo.cwd)),
+        };
+        match memo {
+            Some((cmd, cwd)) => cmds.push((idx.to_string(), cmd, cwd)),
+            None => {
+                eprintln!("not found: {idx}");
+                return 1;
+            }
+        }
+    }
+    if cmds.is_empty() {
+        return 1;
+    }
+    if run {
+        return run_cmds(conn, &cmds, &run_opts);
+    }
+    let text = cmds.iter().map(|(_, cmd, _)| cmd.as_str()).collect::<Vec<_>>().join("\n");
+    let labels = cmds.iter().map(|(idx, ..)| idx.as_str()).collect::<Vec<_>>().join(", ");
+    for (_, cmd, _) in &cmds {
+        record_use(conn, cmd);
+    }
+    if copy_to_clipboard(&text) {
+        eprintln!("copied [{labels}]");
+        return 0;
+    }
+    outln!("{text}");
+    eprintln!("warning: clipboard unavailable");
+    EXIT_COPY_FALLBACK
+}
+
+/// Offers the rows in `fzf --multi` (tab marks, enter confirms) and returns
+/// the chosen indexes in the order fzf prints them.
+fn pick_with_fzf(rows: &[Memo]) -> Vec<usize> {
+    let child = Command::new("fzf")
+        .args(["--multi", "--delimiter=\t", "--with-nth=2..", "--prompt=memo> "])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn();
+    let Ok(mut child) = child else {
+        return Vec::new();
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        for memo in rows {
+            // One line per entry; multi-line commands are shown flattened.
+            let line = format!("{}\t{}\n", memo.index, memo.cmd.replace('\n', " "));
+            if stdin.write_all(line.as_bytes()).is_err() {
+                break;
+            }
+        }
+    }
+    let Ok(output) = child.wait_with_output() else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split('\t').next()?.parse().ok())
+        .collect()
+}
+
+/// Fallback without fzf: lists the rows and reads a comma or space separated
+/// list of numbers.
+fn pick_by_number(rows: &[Memo], opts: &ListOpts) -> Vec<usize> {
+    let now = now_secs();
+    for memo in rows {
+        outln!("{}", format_row(memo, opts, now));
+    }
+    eprint!("memo numbers (e.g. 3,1): ");
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input).is_err() {
+        return Vec::new();
+    }
+    let mut picked = Vec::new();
+    for word in input.split(|c: char| c == ',' || c.is_whitespace()) {
+        if word.is_empty() {
+            continue;
+        }
+        match word.parse() {
+            Ok(idx) => picked.push(idx),
+            Err(_) => {
+                eprintln!("not a number: {word}");
+                return Vec::new();
+            }
+        }
+    }
+    picked
+}
+
+#[derive(Default)]
+struct RunOpts {
+    background: bool,
+    replace_cwd: bool,
+    sandbox: bool,
+    echo: bool,
+    annotate_on_failure: bool,
+    capture_exit: Option<PathBuf>,
+    retry: u32,
+    retry_delay: Duration,
+    keep_going: bool,
+    from_oldest: bool,
+    stdin_from: Option<PathBuf>,
+    log_json: Option<PathBuf>,
+    measure_rss: bool,
+    confirm_word: Option<String>,
+    extra_args: Vec<String>,
+    interactive: bool,
+}
+
+fn run_command(conn: &Connection, args: &[String]) -> i32 {
+    let (opts, positional) = match parse_run_opts(args) {
+        Ok(parsed) => parsed,
+        Err(code) => return code,
+    };
+    if positional.is_empty() {
+        usage();
+        return EXIT_USAGE;
+    }
+    if positional.len() > 1 && !opts.extra_args.is_empty() {
+        eprintln!("arguments after -- need a single command");
+        return EXIT_USAGE;
+    }
+    // Resolve every index up front so a typo doesn't stop a sequence halfway.
+    let mut cmds = Vec::new();
+    for arg in &positional {
+        match lookup_memo(conn, arg, opts.from_oldest) {
+            Ok(memo) => cmds.push((arg.to_string(), memo.cmd, memo.cwd)),
+            Err(code) => return code,
+        }
+    }
+    run_cmds(conn, &cmds, &opts)
+}
+
+/// Parses `run` flags, which may appear anywhere, returning the options and
+/// the remaining positional arguments. Everything after `--` is kept as
+/// arguments to append to the command. Err holds the exit code.
+fn parse_run_opts(args: &[String]) -> Result<(RunOpts, Vec<&String>), i32> {
+    let mut opts = RunOpts::default();
+    let mut positional = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--capture-exit-into" | "--stdin-from" | "--log-json" => {
+                let Some(path) = iter.next() else {
+                    eprintln!("missing value for {arg}");
+                    return Err(EXIT_USAGE);
+                };
+                let path = Some(expand_home(path));
+                match arg.as_str() {
+                    "--stdin-from" => opts.stdin_from = path,
+                    "--log-json" => opts.log_json = path,
+                    _ => opts.capture_exit = path,
+                }
+            }
+            "--retry" | "--retry-delay" => {
+                let Some(value) = iter.next() else {
+                    eprintln!("missing value for {arg}");
+                    return Err(EXIT_USAGE);
+                };
+                let parsed = if arg == "--retry" {
+                    value.parse().map(|n| opts.retry = n).is_ok()
+                } else {
+                    value
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                        .map(|delay| opts.retry_delay = delay)
+                        .is_some()
+                };
+                if !parsed {
+                    eprintln!("invalid {arg}: {value}");
+                    return Err(EXIT_USAGE);
+                }
+            }
+            "--confirm-word" => match iter.next().filter(|w| !w.trim().is_empty()) {
+                Some(word) => opts.confirm_word = Some(word.trim().to_string()),
+                None => {
+                    eprintln!("missing value for --confirm-word");
+                    return Err(EXIT_USAGE);
+                }
+            },
+            "--background" => opts.background = true,
+            "--replace-cwd-placeholder" => opts.replace_cwd = true,
+            "--sandbox" => opts.sandbox = true,
+            "--echo" => opts.echo = true,
+            "--annotate-on-failure" => opts.annotate_on_failure = true,
+            "--from-oldest" => opts.from_oldest = true,
+            "--keep-going" => opts.keep_going = true,
+            "--interactive" => opts.interactive = true,
+            "--measure-and-store-peak-rss" => opts.measure_rss = true,
+            "--" => {
+                opts.extra_args = iter.cloned().collect();
+                break;
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if opts.background && opts.retry > 0 {
+        eprintln!("--retry cannot be combined with --background");
+        return Err(EXIT_USAGE);
+    }
+    if opts.background && opts.measure_rss {
+        eprintln!("--measure-and-store-peak-rss cannot be combined with --background");
+        return Err(EXIT_USAGE);
+    }
+    Ok((opts, positional))
+}
+
+/// A resolved command to run: its label for reporting, the command and the
+/// directory it was saved in.
+type RunTarget = (String, String, String);
+
+/// Runs resolved commands, each labelled for reporting. Several commands
+/// run in order, like a runbook.
+fn run_cmds(conn: &Connection, cmds: &[RunTarget], opts: &RunOpts) -> i32 {
+    if opts.sandbox && which("bwrap").is_none() {
+        eprintln!("sandbox unavailable: bwrap not found; not running");
+        return 1;
+    }
+    if let Err(err) = child_stdin(opts) {
+        eprintln!("{err}");
+        return 1;
+    }
+    if let [(_, cmd, cwd)] = cmds {
+        return match run_one(conn, cmd, cwd, opts) {
+            Ok(code) => {
+                capture_exit(opts, code);
+                code
+            }
+            Err(code) => code,
+        };
+    }
+    // The first failure stops the rest unless --keep-going, and memo exits
+    // with that failure's code.
+    let mut result = 0;
+    for (label, cmd, cwd) in cmds {
+        let code = run_one(conn, cmd, cwd, opts).unwrap_or_else(|code| code);
+        eprintln!("[{label}] exit {code}");
+        if code != 0 && result == 0 {
+            result = code;
+        }
+        if (code != 0 && !opts.keep_going) || pending_signal() != 0 {
+            break;
+        }
+    }
+    capture_exit(opts, result);
+    result
+}
+
+/// Runs one resolved command with the danger check, echo, background and
+/// retry handling, in the directory it was saved in if that still exists.
+/// Returns Err with the exit code when the command was not run because the
+/// user declined the danger prompt.
+fn run_one(conn: &Connection, stored: &str, cwd: &str, opts: &RunOpts) -> Result<i32, i32> {
+    let dir = Some(Path::new(cwd)).filter(|dir| !cwd.is_empty() && dir.is_dir());
+    if dir.is_none() && !cwd.is_empty() {
+        eprintln!("warning: {cwd} no longer exists; running in the current directory");
+    }
+    let mut cmd = stored.to_string();
+    for arg in &opts.extra_args {
+        cmd.push(' ');
+        cmd.push_str(arg);
+    }
+    if opts.replace_cwd {
+        let cwd = env::current_dir().unwrap_or_default();
+        cmd = cmd.replace("{cwd}", &cwd.to_string_lossy());
+    }
+    // MEMO_NO_DANGER=1 is for scripts: nothing asks, critical or not.
+    let no_danger = env::var("MEMO_NO_DANGER").is_ok_and(|v| v == "1");
+    let critical = match is_critical(&cmd) {
+        Ok(critical) => !no_danger && critical,
+        Err(err) => {
+            eprintln!("{err}");
+            return Err(1);
+        }
+    };
+    let confirmed = if critical {
+        let program = command_program(&cmd).unwrap_or_default();
+        confirm_word(opts.confirm_word.as_deref().unwrap_or(program))
+    } else {
+        no_danger || !is_dangerous(&cmd) || confirm_run()
+    };
+    if !confirmed {
+        log_run("declined", &cmd);
+        return Err(1);
+    }
+    record_use(conn, stored);
+    if opts.echo {
+        eprintln!("+ {cmd}");
+    }
+    if opts.background {
+        let code = spawn_background(&cmd, dir, opts);
+        log_run(if code == 0 { "background" } else { "failed" }, &cmd);
+        return Ok(code);
+    }
+    let attempts = opts.retry.saturating_add(1);
+    let mut code = 0;
+    let mut peak_rss = None;
+    for attempt in 1..=attempts {
+        // Reopened for every attempt so each one reads the file from the start.
+        let stdin = match child_stdin(opts) {
+            Ok(stdin) => stdin,
+            Err(err) => {
+                eprintln!("{err}");
+                return Ok(1);
+            }
+        };
+        let started = (now_secs(), Instant::now());
+        let mut command = shell_command(&cmd, dir, opts);
+        command.stdin(stdin);
+        let status = if opts.measure_rss {
+            status_with_peak_rss(command)
+        } else {
+            command.status().map(|status| (status, None))
+        };
+        code = match status {
+            Ok((status, rss)) => {
+                peak_rss = rss;
+                status.code().unwrap_or(1)
+            }
+            Err(err) => shell_spawn_error(&run_shell(), &err),
+        };
+        log_run_json(opts, &cmd, dir, code, started);
+        if opts.retry > 0 {
+            eprintln!("attempt {attempt}/{attempts}: exit {code}");
+        }
+        // A missing shell won't appear between attempts.
+        if code == 0 || code == EXIT_SHELL_NOT_FOUND || code == EXIT_CANNOT_EXEC {
+            break;
+        }
+        if attempt < attempts {
+            thread::sleep(opts.retry_delay);
+        }
+    }
+    log_run(&code.to_string(), &cmd);
+    if let Some(rss) = peak_rss.filter(|_| !refuse_write("--measure-and-store-peak-rss")) {
+        let _signals = DeferSignals::new();
+        let stored = conn.execute(
+            "UPDATE memos SET last_peak_rss = ? \
+             WHERE id IN (SELECT id FROM plain_memos WHERE cmd = ?)",
+            params![rss, stored],
+        );
+        if let Err(err) = stored {
+            eprintln!("could not store peak RSS: {err}");
+        }
+    }
+    if code != 0 && opts.annotate_on_failure && !refuse_write("--annotate-on-failure") {
+        annotate_failure(conn, stored, code);
+    }
+    Ok(code)
+}
+
+/// One step of a named sequence: the memo's current index and command, or
+/// None when the memo has since been deleted.
+type SeqStep = Option<(usize, String, String)>;
+
+fn seq_steps(conn: &Connection, name: &str) -> rusqlite::Result<Vec<SeqStep>> {
+    let mut stmt = conn.prepare(
+        "SELECT m.idx, m.cmd, m.cwd FROM sequences s LEFT JOIN (\
+         SELECT ROW_NUMBER() OVER (ORDER BY created_at DESC, id DESC) AS idx, id, cmd, cwd \
+         FROM plain_memos) m \
+         ON m.id = s.memo_id WHERE s.name = ? ORDER BY s.position",
+    )?;
+    let rows = stmt.query_map(params![name], |row| {
+        let idx: Option<i64> = row.get(0)?;
+        let cmd: Option<String> = row.get(1)?;
+        let cwd: Option<String> = row.get(2)?;
+        Ok(idx.zip(cmd.zip(cwd)).map(|(idx, (cmd, cwd))| (idx as usize, cmd, cwd)))
+    })?;
+    rows.collect()
+}
+
+fn seq_names(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT DISTINCT name FROM sequences ORDER BY name")?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Stores `ids` under `name` in order, replacing any sequence of that name.
+fn save_seq(conn: &mut Connection, name: &str, ids: &[i64]) -> rusqlite::Result<()> {
+    let _signals = DeferSignals::new();
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM sequences WHERE name = ?", params![name])?;
+    for (position, id) in ids.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO sequences (name, position, memo_id) VALUES (?, ?, ?)",
+            params![name, position as i64, id],
+        )?;
+    }
+    tx.commit()
+}
+
+fn seq_command(conn: &mut Connection, args: &[String]) -> i32 {
+    if matches!(args.first().map(String::as_str), Some("save" | "rm")) && refuse_write("seq") {
+        return 1;
+    }
+    let result = match args {
+        [sub, name, indexes @ ..] if sub == "save" && !indexes.is_empty() => {
+            let mut ids = Vec::new();
+            for arg in indexes {
+                let Some((idx, oldest)) = parse_index(arg) else {
+                    usage();
+                    return EXIT_USAGE;
+                };
+                match id_by_index(conn, idx, oldest).ok().flatten() {
+                    Some(id) => ids.push(id),
+                    None => {
+                        eprintln!("not found: {arg}");
+                        return 1;
+                    }
+                }
+            }
+            save_seq(conn, name, &ids)
+                .map(|()| outln!("saved sequence {name} ({} steps)", ids.len()))
+        }
+        [sub] if sub == "list" => seq_names(conn).and_then(|names| {
+            if names.is_empty() {
+                outln!("no sequences");
+            }
+            for name in names {
+                outln!("{name}");
+                for step in seq_steps(conn, &name)? {
+                    match step {
+                        Some((idx, cmd, _)) => outln!("  [{idx}] {cmd}"),
+                        None => outln!("  (deleted)"),
+                    }
+                }
+            }
+            Ok(())
+        }),
+        [sub, name] if sub == "rm" => {
+            let _signals = DeferSignals::new();
+            match conn.execute("DELETE FROM sequences WHERE name = ?", params![name]) {
+                Ok(0) => {
+                    eprintln!("no sequence named {name}");
+                    return 1;
+                }
+                result => result.map(|_| outln!("removed sequence {name}")),
+            }
+        }
+        _ => {
+            usage();
+            return EXIT_USAGE;
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("db error: {err}");
+            1
+        }
+    }
+}
+
+/// `memo run-seq <name>`: runs a named sequence with the usual run flags.
+/// Steps whose memo was deleted abort before anything runs, or are skipped
+/// with a warning under `--on-missing skip`.
+fn run_seq_command(conn: &Connection, args: &[String]) -> i32 {
+    let mut skip_missing = false;
+    let mut rest = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg != "--on-missing" {
+            rest.push(arg.clone());
+            continue;
+        }
+        match iter.next().map(String::as_str) {
+            Some("skip") => skip_missing = true,
+            Some("abort") => skip_missing = false,
+            other => {
+                let value = other.unwrap_or_default();
+                eprintln!("invalid --on-missing: {value} (expected skip or abort)");
+                return EXIT_USAGE;
+            }
+        }
+    }
+    let (opts, positional) = match parse_run_opts(&rest) {
+        Ok(parsed) => parsed,
+        Err(code) => return code,
+    };
+    let [name] = positional.as_slice() else {
+        usage();
+        return EXIT_USAGE;
+    };
+    if !opts.extra_args.is_empty() {
+        eprintln!("run-seq does not take arguments after --");
+        return EXIT_USAGE;
+    }
+    let steps = match seq_steps(conn, name) {
+        Ok(steps) => steps,
+        Err(err) => {
+            eprintln!("db error: {err}");
+            return 1;
+        }
+    };
+    if steps.is_empty() {
+        eprintln!("no sequence named {name}");
+        return EXIT_NOT_FOUND;
+    }
+    let mut cmds = Vec::new();
+    for (position, step) in steps.into_iter().enumerate() {
+        match step {
+            Some((idx, cmd, cwd)) => cmds.push((idx.to_string(), cmd, cwd)),
+            None if skip_missing => {
+                eprintln!("warning: step {} of {name} was deleted; skipping", position + 1);
+            }
+            None => {
+                eprintln!(
+                    "step {} of {name} was deleted; not running (use --on-missing skip)",
+                    position + 1
+                );
+                return 1;
+            }
+        }
+    }
+    if cmds.is_empty() {
+        eprintln!("nothing to run");
+        return 1;
+    }
+    run_cmds(conn, &cmds, &opts)
+}
+
+/// Writes the exit code, and nothing else, to the `--capture-exit-into` file.
+fn capture_exit(opts: &RunOpts, code: i32) {
+    if let Some(path) = &opts.capture_exit {
+        if let Err(err) = fs::write(path, format!("{code}\n")) {
+            eprintln!("warning: could not write exit code to {}: {err}", path.display());
+        }
+    }
+}
+
+/// Asks for a one-line note after a failed run and stores it on every saved
+/// copy of the command. An empty answer keeps the current note.
+fn annotate_failure(conn: &Connection, cmd: &str, code: i32) {
+    if !io::stdin().is_terminal() {
+        return;
+    }
+    eprint!("exit {code}; note why it failed (empty to skip): ");
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input).is_err() {
+        return;
+    }
+    let note = input.trim();
+    if note.is_empty() {
+        return;
+    }
+    let _signals = DeferSignals::new();
+    if let Err(err) = conn.execute(
+        "UPDATE memos SET note = ? WHERE id IN (SELECT id FROM plain_memos WHERE cmd = ?)",
+        params![note, cmd],
+    ) {
+        eprintln!("could not save note: {err}");
+    }
+}
+
+/// Appends `<unix time>\t<outcome>\t<command>` to `$MEMO_RUN_LOG` when set.
+/// The outcome is the exit code, or `declined` when a dangerous command was
+/// refused at the prompt.
+fn log_run(outcome: &str, cmd: &str) {
+    let Some(path) = env::var_os("MEMO_RUN_LOG").filter(|p| !p.is_empty()) else {
+        return;
+    };
+    let line = format!("{}\t{outcome}\t{}\n", now_secs(), cmd.replace('\n', "\\n"));
+    let file = fs::OpenOptions::new().create(true).append(true).open(path);
+    if let Err(err) = file.and_then(|mut f| f.write_all(line.as_bytes())) {
+        eprintln!("warning: could not write run log: {err}");
+    }
+}
+
+/// Appends one JSON object for a finished run to the `--log-json` file,
+/// creating it if needed. The line goes out in a single append write, so
+/// concurrent memo runs logging to the same file don't interleave.
+fn log_run_json(
+    opts: &RunOpts,
+    cmd: &str,
+    dir: Option<&Path>,
+    code: i32,
+    (ts, started): (i64, Instant),
+) {
+    let Some(path) = &opts.log_json else {
+        return;
+    };
+    let cwd = dir.map_or_else(|| env::current_dir().unwrap_or_default(), Path::to_path_buf);
+    let line = format!(
+        "{{\"ts\":{ts},\"cmd\":{},\"exit\":{code},\"duration_ms\":{},\"cwd\":{}}}\n",
+        json_string(cmd),
+        started.elapsed().as_millis(),
+        json_string(&cwd.to_string_lossy())
+    );
+    let file = fs::OpenOptions::new().create(true).append(true).open(path);
+    if let Err(err) = file.and_then(|mut f| f.write_all(line.as_bytes())) {
+        eprintln!("warning: could not write {}: {err}", path.display());
+    }
+}
+
+/// The replayed command's stdin: the `--stdin-from` file, or memo's own stdin.
+/// memo keeps its stdin either way, so the danger prompt still reads the tty.
+fn child_stdin(opts: &RunOpts) -> Result<Stdio, String> {
+    match &opts.stdin_from {
+        Some(path) => fs::File::open(path)
+            .map(Stdio::from)
+            .map_err(|err| format!("cannot read {}: {err}", path.display())),
+        None => Ok(Stdio::inherit()),
+    }
+}
+
+/// Builds the `<shell> -c <cmd>` invocation (`-i -c` with `--interactive`),
+/// wrapped in bubblewrap with a read-only root and a private writable /tmp
+/// when sandboxed.
+fn shell_command(cmd: &str, dir: Option<&Path>, opts: &RunOpts) -> Command {
+    let shell = run_shell();
+    let mut command = if opts.sandbox {
+        let mut command = Command::new("bwrap");
+        command
+            .args(["--ro-bind", "/", "/"])
+            .args(["--dev", "/dev"])
+            .args(["--proc", "/proc"])
+            .args(["--tmpfs", "/tmp"]);
+        // A background command outlives memo on purpose.
+        if !opts.background {
+            command.arg("--die-with-parent");
+        }
+        command.arg(&shell);
+        command
+    } else {
+        Command::new(&shell)
+    };
+    if opts.interactive {
+        command.arg("-i");
+    }
+    command.arg("-c").arg(cmd);
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+    command
+}
+
+/// The shell `run` uses: `$MEMO_RUN_SHELL`, else `$MEMO_SHELL`, else `$SHELL`
+/// if it looks like a usable shell, else `sh`.
+fn run_shell() -> String {
+    let set = |var: &str| env::var(var).ok().filter(|s| !s.is_empty());
+    if let Some(shell) = set("MEMO_RUN_SHELL").or_else(|| set("MEMO_SHELL")) {
+        return shell;
+    }
+    // Accounts that can't log in have `$SHELL` set to a program that refuses.
+    let usable = |shell: &str| {
+        let name = Path::new(shell).file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        let exists = if shell.contains('/') {
+            Path::new(shell).is_file()
+        } else {
+            which(shell).is_some()
+        };
+        exists && !matches!(name, "nologin" | "false")
+    };
+    set("SHELL").filter(|s| usable(s)).unwrap_or_else(|| "sh".to_string())
+}
+
+fn shell_spawn_error(shell: &str, err: &io::Error) -> i32 {
+    if err.kind() == io::ErrorKind::NotFound {
+        eprintln!("shell '{shell}' not found; set $MEMO_RUN_SHELL");
+        EXIT_SHELL_NOT_FOUND
+    } else {
+        eprintln!("failed to start shell '{shell}': {err}");
+        EXIT_CANNOT_EXEC
+    }
+}
+
+// Declared by hand, so only for Linux and macOS: struct rusage below is
+// their layout, and other targets do without peak RSS and setsid.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+extern "C" {
+    fn setsid() -> i32;
+    fn wait4(pid: i32, status: *mut i32, options: i32, rusage: *mut Rusage) -> i32;
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[repr(C)]
+struct Rusage {
+    ru_utime: [std::ffi::c_long; 2],
+    ru_stime: [std::ffi::c_long; 2],
+    ru_maxrss: std::ffi::c_long,
+    ru_rest: [std::ffi::c_long; 13],
+}
+
+/// Runs `command` to completion and reads its peak resident set size, in
+/// bytes, from wait4. The figure covers the shell and every process it
+/// waited for. None where wait4 isn't available.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn status_with_peak_rss(mut command: Command) -> io::Result<(ExitStatus, Option<i64>)> {
+    use std::os::unix::process::ExitStatusExt;
+    let child = command.spawn()?;
+    let pid = child.id() as i32;
+    let mut status = 0;
+    let mut usage = std::mem::MaybeUninit::<Rusage>::zeroed();
+    // SAFETY: wait4 only writes into status and the zeroed Rusage we pass it.
+    while unsafe { wait4(pid, &mut status, 0, usage.as_mut_ptr()) } != pid {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    // SAFETY: wait4 succeeded, so it filled in the struct.
+    let usage = unsafe { usage.assume_init() };
+    // Linux reports KiB, macOS bytes.
+    let scale = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    #[allow(clippy::unnecessary_cast)]
+    let peak = usage.ru_maxrss as i64 * scale;
+    Ok((ExitStatus::from_raw(status), Some(peak)))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn status_with_peak_rss(mut command: Command) -> io::Result<(ExitStatus, Option<i64>)> {
+    command.status().map(|status| (status, None))
+}
+
+/// Starts `cmd` in its own session with output appended to a log file under
+/// the state directory, and returns without waiting for it.
+fn spawn_background(cmd: &str, dir: Option<&Path>, opts: &RunOpts) -> i32 {
+    let log_path = state_dir().join("background.log");
+    let log = fs::OpenOptions::new().create(true).append(true).open(&log_path);
+    let (stdout, stderr) = match log.and_then(|f| Ok((f.try_clone()?, f))) {
+        Ok((out, err)) => (Stdio::from(out), Stdio::from(err)),
+        Err(_) => (Stdio::null(), Stdio::null()),
+    };
+    let stdin = match opts.stdin_from {
+        Some(_) => child_stdin(opts).unwrap_or_else(|_| Stdio::null()),
+        None => Stdio::null(),
+    };
+    let mut command = shell_command(cmd, dir, opts);
+    command
+        .stdin(stdin)
+        .stdout(stdout)
+        .stderr(stderr);
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: setsid is async-signal-safe and touches no parent state.
+        unsafe {
+            command.pre_exec(|| {
+                setsid();
+                Ok(())
+            });
+        }
+    }
+    match command.spawn() {
+        Ok(child) => {
+            outln!("started pid {} (log: {})", child.id(), log_path.display());
+            0
+        }
+        Err(err) => shell_spawn_error(&run_shell(), &err),
+    }
+}
+
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const SIGHUP: i32 = 1;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const SIGTERM: i32 = 15;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+extern "C" {
+    // A null handler is SIG_DFL.
+    fn signal(signum: i32, handler: Option<extern "C" fn(i32)>) -> usize;
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+extern "C" fn record_signal(sig: i32) {
+    PENDING_SIGNAL.store(sig, Ordering::SeqCst);
+}
+
+/// With `defer` true, SIGHUP and SIGTERM are noted instead of acted on; with
+/// it false they get their default action back.
+fn defer_signals(defer: bool) {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        let handler: Option<extern "C" fn(i32)> = if defer { Some(record_signal) } else { None };
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+        unsafe {
+            signal(SIGHUP, handler);
+            signal(SIGTERM, handler);
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    let _ = defer;
 }
 
-fn cmd_by_index(conn: &Connection, index: usize) -> rusqlite::Result<Option<String>> {
-    if index < 1 {
-        return Ok(None);
-    }
-    conn.query_row(
-        "SELECT cmd FROM memos ORDER BY id DESC LIMIT 1 OFFSET ?",
-        params![index as i64 - 1],
-        |row| row.get(0),
-    )
-    .optional()
+fn pending_signal() -> i32 {
+    PENDING_SIGNAL.load(Ordering::SeqCst)
 }
 
-fn read_last_history_command() -> Option<String> {
-    let histfile = env::var("HISTFILE")
-        .ok()
-        .map(PathBuf::from)
-        .unwrap_or_else(|| expand_home("~/.zsh_history"));
-    if !histfile.exists() {
-        return None;
-    }
-    let mut file = fs::File::open(histfile).ok()?;
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf).ok()?;
-    let content = String::from_utf8_lossy(&buf);
-    for line in content.lines().rev() {
-        if line.is_empty() {
-            continue;
-        }
-        let mut cmd = line;
-        if let Some(rest) = line.strip_prefix(':') {
-            if let Some((_, after)) = rest.split_once(';') {
-                cmd = after;
-            }
-        }
-        let cmd = cmd.trim();
-        if cmd.is_empty() {
-            continue;
-        }
-        if cmd == "memo" || cmd.starts_with("memo ") {
-            continue;
+static DEFER_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// memo is often run from prompt hooks that get killed along with the
+/// terminal. Held from before a database write until it is done, so
+/// SIGHUP/SIGTERM can't kill memo halfway through; a signal that arrives
+/// meanwhile makes memo exit with 128+signal once the outermost guard drops.
+/// Outside writes (prompts, the editor, run's child) the signals keep their
+/// default action.
+struct DeferSignals;
+
+impl DeferSignals {
+    fn new() -> Self {
+        if DEFER_DEPTH.fetch_add(1, Ordering::SeqCst) == 0 {
+            defer_signals(true);
         }
-        return Some(cmd.to_string());
+        DeferSignals
     }
-    None
 }
 
-fn expand_home(path: &str) -> PathBuf {
-    if let Some(stripped) = path.strip_prefix("~/") {
-        let home = env::var_os("HOME").unwrap_or_default();
-        return Path::new(&home).join(stripped);
+impl Drop for DeferSignals {
+    fn drop(&mut self) {
+        if DEFER_DEPTH.fetch_sub(1, Ordering::SeqCst) == 1 {
+            defer_signals(false);
+            let sig = pending_signal();
+            if sig != 0 {
+                std::process::exit(128 + sig);
+            }
+        }
     }
-    PathBuf::from(path)
 }
 
-fn is_dangerous(cmd: &str) -> bool {
-    let patterns = [
-        r"\brm\b",
-        r"\bsudo\b",
-        r"\bdd\b",
-        r"\bmkfs",
-        r"\bshutdown\b",
-        r"\breboot\b",
-        r"\bpoweroff\b",
-        r"\|\s*sh\b",
-    ];
-    patterns.iter().any(|pat| {
-        Regex::new(pat)
-            .map(|re| re.is_match(cmd))
-            .unwrap_or(false)
-    })
-}
+const STATS_BAR_WIDTH: usize = 40;
+const STATS_DAYS: usize = 14;
 
-fn confirm_run() -> bool {
-    print!("dangerous command, run? [y/N] ");
-    let _ = io::stdout().flush();
-    let mut input = String::new();
-    if io::stdin().read_line(&mut input).is_err() {
-        return false;
-    }
-    matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+fn print_bar(label: &str, count: usize, max: usize) {
+    let width = if max == 0 { 0 } else { (count * STATS_BAR_WIDTH).div_ceil(max) };
+    outln!("{label} {count:>4} {}", "#".repeat(width));
 }
 
-fn which(cmd: &str) -> Option<PathBuf> {
-    let paths = env::var_os("PATH")?;
-    for path in env::split_paths(&paths) {
-        let candidate = path.join(cmd);
-        if candidate.exists() {
-            return Some(candidate);
+/// `memo top [N]`: the N most copied and run commands, each shown once at
+/// its newest index with the uses of all its copies added up.
+fn top_command(conn: &Connection, args: &[String]) -> i32 {
+    let limit = match args {
+        [] => DEFAULT_LIMIT,
+        [n] => match n.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("invalid count: {n}");
+                return EXIT_USAGE;
+            }
+        },
+        _ => {
+            usage();
+            return EXIT_USAGE;
+        }
+    };
+    let rows = conn
+        .prepare(
+            "SELECT idx, total, cmd FROM (\
+             SELECT ROW_NUMBER() OVER (ORDER BY created_at DESC, id DESC) AS idx, \
+             ROW_NUMBER() OVER (PARTITION BY cmd ORDER BY created_at DESC, id DESC) AS dup, \
+             SUM(uses) OVER (PARTITION BY cmd) AS total, cmd FROM plain_memos) \
+             WHERE dup = 1 AND total > 0 ORDER BY total DESC, idx ASC LIMIT ?",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(params![limit as i64], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, String>(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+        });
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(err) => {
+            eprintln!("db error: {err}");
+            return 1;
         }
+    };
+    if rows.is_empty() {
+        outln!("nothing copied or run yet");
     }
-    None
+    for (idx, uses, cmd) in rows {
+        outln!("[{idx}] ({uses}x) {cmd}");
+    }
+    0
 }
 
-fn clipboard_command() -> Option<Vec<String>> {
-    if cfg!(target_os = "macos") {
-        if which("pbcopy").is_some() {
-            return Some(vec!["pbcopy".to_string()]);
+fn stats_command(conn: &Connection, args: &[String]) -> i32 {
+    let by_hour = match args {
+        [] => false,
+        [flag] if flag == "--by-hour" => true,
+        _ => {
+            usage();
+            return EXIT_USAGE;
         }
-        return None;
+    };
+    let times = conn
+        .prepare("SELECT created_at FROM memos ORDER BY created_at DESC, id DESC")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, i64>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        });
+    let times = match times {
+        Ok(times) => times,
+        Err(err) => {
+            eprintln!("db error: {err}");
+            return 1;
+        }
+    };
+    let distinct: i64 = conn
+        .query_row("SELECT COUNT(DISTINCT cmd) FROM plain_memos", [], |row| row.get(0))
+        .unwrap_or(0);
+    outln!("total: {} memos ({distinct} distinct)", times.len());
+    if times.is_empty() {
+        return 0;
     }
-    if which("wl-copy").is_some() {
-        return Some(vec!["wl-copy".to_string()]);
+
+    if by_hour {
+        let mut hours = [0usize; 24];
+        for ts in &times {
+            hours[local_time(*ts).hour as usize] += 1;
+        }
+        let max = hours.iter().copied().max().unwrap_or(0);
+        outln!("by hour:");
+        for (hour, count) in hours.iter().enumerate() {
+            print_bar(&format!("{hour:02}"), *count, max);
+        }
+        return 0;
     }
-    if which("xclip").is_some() {
-        return Some(vec!["xclip".to_string(), "-selection".to_string(), "clipboard".to_string()]);
+
+    let mut per_day: BTreeMap<String, usize> = BTreeMap::new();
+    for ts in &times {
+        let t = local_time(*ts);
+        *per_day
+            .entry(format!("{:04}-{:02}-{:02}", t.year, t.month, t.day))
+            .or_default() += 1;
     }
-    if which("xsel").is_some() {
-        return Some(vec![
-            "xsel".to_string(),
-            "--clipboard".to_string(),
-            "--input".to_string(),
-        ]);
+    let days: Vec<(String, usize)> = per_day.into_iter().rev().take(STATS_DAYS).collect();
+    let max = days.iter().map(|(_, count)| *count).max().unwrap_or(0);
+    outln!("by day (last {} active days):", days.len());
+    for (day, count) in &days {
+        print_bar(day, *count, max);
     }
-    None
+    0
 }
 
-fn copy_to_clipboard(text: &str) -> bool {
-    let cmd = match clipboard_command() {
-        Some(cmd) => cmd,
-        None => return false,
-    };
-    let mut child = match Command::new(&cmd[0])
-        .args(&cmd[1..])
-        .stdin(Stdio::piped())
-        .spawn()
-    {
-        Ok(child) => child,
-        Err(_) => return false,
+/// `memo doctor [--check-binaries]`: reports saved commands whose program is
+/// no longer on PATH. That is the only check so far, so it also runs by
+/// default.
+fn doctor_command(conn: &Connection, args: &[String]) -> i32 {
+    let known = match args {
+        [] => true,
+        [flag] => flag == "--check-binaries",
+        _ => false,
     };
-    if let Some(mut stdin) = child.stdin.take() {
-        if stdin.write_all(text.as_bytes()).is_err() {
-            return false;
+    if !known {
+        usage();
+        return EXIT_USAGE;
+    }
+    let mut opts = ListOpts::new(usize::MAX, None);
+    opts.distinct = true;
+    let memos = match list_cmds(conn, &opts) {
+        Ok(memos) => memos,
+        Err(err) => {
+            eprintln!("db error: {err}");
+            return 1;
         }
+    };
+    let missing: Vec<&Memo> = memos.iter().filter(|m| !binary_exists(&m.cmd)).collect();
+    for memo in &missing {
+        let program = command_program(&memo.cmd).unwrap_or_default();
+        outln!("[{}] {program}: {}", memo.index, memo.cmd);
     }
-    child.wait().map(|s| s.success()).unwrap_or(false)
+    outln!(
+        "{} of {} distinct commands reference a program not on PATH",
+        missing.len(),
+        memos.len()
+    );
+    0
 }
 
 fn usage() {
-    println!(
-        "usage:\n\
-  memo                 save last command and list\n\
-  memo <query>          list filtered commands\n\
-  memo <N>              copy command N\n\
-  memo run <N>          execute command N\n\
-  memo print <N>        print command N\n\
-  memo list [query]     list commands\n\
-  memo save [cmd...]    save last or explicit command\n"
+    outln!(
+        "usage:
+  memo                    save last command and list
+  memo <query>            list filtered commands
+  memo <N>                copy command N
+      --set-title         also set the terminal title to the copied command
+  memo run [opts] <N>...  execute command N (^N or --from-oldest: Nth oldest);
+                          several run in order, stopping at the first failure
+  memo run [opts] <N> -- <args>...
+                          append args to command N before running it
+      --keep-going        with several commands, run the rest after a failure
+      --interactive       run the shell with -i, so interactive aliases load
+      --background        detach and log output to the state directory
+      --echo              print + <command> to stderr before running
+      --retry <N>         rerun up to N more times until it exits 0
+      --retry-delay <secs>
+                          wait between retries (default 0)
+      --stdin-from <file> feed file to the command's stdin
+      --capture-exit-into <file>
+                          write the command's exit code to file
+      --log-json <file>   append a JSON record of each run to file
+      --confirm-word <word>
+                          word to type before running a critical command
+                          (default: the command's program name)
+      --annotate-on-failure
+                          on nonzero exit, ask for a note shown in listings
+      --sandbox           run under bwrap with a read-only filesystem
+      --measure-and-store-peak-rss
+                          record the command's peak memory (see list --rss)
+      --replace-cwd-placeholder
+                          replace {{cwd}} with the current directory
+  memo pick [--copy|--run] [list opts]
+                          choose commands (fzf --multi, else type numbers);
+                          copy them all or run them in order
+  memo seq save <name> <N>...
+                          save commands N... as a named sequence
+  memo seq list           show sequences and their commands
+  memo seq rm <name>      delete a sequence
+  memo run-seq [opts] <name>
+                          run a sequence's commands in order (takes run's opts)
+      --on-missing <abort|skip>
+                          when a step's command was deleted (default abort)
+  memo print <N>          print command N (^N or --from-oldest: Nth oldest)
+                          print and run also take a hash prefix (--fields hash)
+      --numbered-lines    prefix each line of the command with its number
+      --json              print the command's row as one JSON object
+  memo stats [--by-hour]  count saved commands per day or per hour of day
+  memo top [N]            the N most copied and run commands (default 10)
+  memo doctor [--check-binaries]
+                          list commands whose program is no longer on PATH
+  memo prune --duplicates-only
+                          delete entries identical to the next newer one
+  memo tag <N> <tag...>   add tags to command N
+  memo tag <N> --remove <tag...>
+                          remove tags from command N
+  memo edit <N>           change command N in $EDITOR (else $VISUAL, else vi)
+  memo touch <N>          move command N to the top and refresh its time
+  memo pin <N>            keep command N when MEMO_CAP evicts old entries
+  memo unpin <N>          let command N be evicted again
+  memo delete <N>[,N...]  delete commands N... (indexes as shown by list)
+  memo clear [-f|--force] delete every command, after asking unless --force
+  memo merge <file>       add commands from another memo database
+  memo import --from-shell-history <file> [--shell zsh|bash|fish|pwsh]
+                          add commands from a shell history file
+  memo resolve <ref>      print the command an index or unique query refers to
+  memo completion <bash|zsh|fish>
+                          print a shell completion script
+  memo list [query]       list commands
+      --distinct          show each command once, at its newest index
+      --time[=iso]        show how long ago each command was saved, or when
+      --rss               show the peak memory of each command's last measured run
+      --show-cwd          show the directory each command was saved in
+      --files             search the shell history files instead of saved memos
+      --smart-case        match case only if the query has uppercase letters
+      --case-sensitive    always match case
+      --ignore-case       never match case (the default)
+      --word              match the query only as a whole word
+      --regex             treat the query as a regular expression
+      --fuzzy             match the query's characters in order (gco: git checkout),
+                          tightest matches first
+      --limit <N|all|-N>  show N rows, all rows (also 0), or all but the N most recent
+      --oldest-n <N>      show the N oldest rows, oldest first
+      --since-id <ID>     show rows with an id above ID, in id order, all by default
+      --json              print rows as a JSON array
+      --ndjson            print one JSON object per line, streamed
+      --json-schema       print the JSON Schema of --json/--ndjson rows
+      --out <file>        write the listing to a file instead of stdout
+      --source <label>    only rows saved from this source (manual, history, import)
+      --repo <name>       only rows saved in this git repository (see --git-context)
+      --branch <name>     only rows saved on this git branch
+      --fields <f[,f...]> aligned columns: index, id, cmd, age, created, tags,
+                          note, source, hash, repo, branch, cwd, rss
+      --fields-width <f=N[,f=N...]>
+                          pad or truncate these columns to N characters
+      --relative-paths    show cwd relative to here, or with ~ for home
+      --active-only       only commands you could run now: --hide-ignored and
+                          --only-existing-binary (add --no-dangerous too)
+      --hide-ignored      skip commands matching a regex in ~/.config/memo/ignore
+      --only-existing-binary
+                          skip commands whose program isn't on PATH
+      --no-dangerous      skip commands that would ask for confirmation
+      --last-query        reuse the previous query; extra words refine it
+      --clear-query       forget the previous query
+      --tag <t[,t...]>    only rows with any of these tags (same as --tag-any)
+      --tag-all <t[,t...]>
+                          only rows with all of these tags
+  memo save [opts] [cmd...]
+                          save last or explicit command
+      --detect-secrets-only-warn
+                          warn about likely secrets but save as-is
+      --strip-comments    drop an unquoted trailing # comment
+      --trim-trailing-amp drop a trailing & (also MEMO_TRIM_TRAILING_AMP=1)
+      --dedup             move an already saved copy to the top instead of
+                          saving it again (also MEMO_DEDUP=1)
+      --source <label>    record where the command came from
+      --git-context       record the git repository and branch
+      --replace-last      overwrite the newest memo instead of adding one
+      --event <N>         save history event N (counted from the history file)
+      --shell <name>      read the history of zsh, bash, fish or pwsh
+                          (default: from $MEMO_SHELL or $SHELL)
+      --now               timestamp with the current time, not the history time
+      --if-changed-dir    skip unless the directory differs from the last memo's
+      --verbose           say why a save was skipped
+
+options:
+  --local                 use the nearest .memo.sqlite3 in this or a parent directory
+  --read-only             never modify the database (also MEMO_READONLY=1)
+  --json                  print listings and print's output as JSON
+"
     );
 }
 
-fn main() -> i32 {
-    let args: Vec<String> = env::args().skip(1).collect();
-    if matches!(args.get(0).map(String::as_str), Some("-h" | "--help")) {
+fn main() {
+    std::process::exit(real_main());
+}
+
+fn real_main() -> i32 {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if matches!(args.first().map(String::as_str), Some("-h" | "--help")) {
         usage();
         return 0;
     }
+    let mut local = false;
+    let mut readonly = env::var("MEMO_READONLY").is_ok_and(|v| v == "1");
+    while let Some(flag) = args.first() {
+        match flag.as_str() {
+            "--local" => local = true,
+            "--read-only" => readonly = true,
+            "--json" => JSON_OUTPUT.store(true, Ordering::Relaxed),
+            _ => break,
+        }
+        args.remove(0);
+    }
+    READ_ONLY.store(readonly, Ordering::Relaxed);
 
-    let conn = match connect_db() {
+    let mut conn = match connect_db(local) {
         Ok(conn) => conn,
         Err(err) => {
             eprintln!("db error: {err}");
@@ -264,120 +4445,319 @@ fn main() -> i32 {
         }
     };
 
-    if args.is_empty() {
-        if let Some(last_cmd) = read_last_history_command() {
-            let last_saved = last_saved_cmd(&conn).ok().flatten();
-            if last_saved.as_deref() != Some(&last_cmd) {
-                let _ = insert_cmd(&conn, &last_cmd);
-            }
-        }
-        let rows = list_cmds(&conn, DEFAULT_LIMIT, None).unwrap_or_default();
-        if rows.is_empty() {
-            println!("no entries");
-            return 0;
-        }
-        for (idx, cmd) in rows {
-            println!("[{idx}] {cmd}");
-        }
-        return 0;
-    }
+    let code = dispatch(&mut conn, &args);
+    let _ = conn.close();
+    code
+}
 
-    match args[0].as_str() {
+fn dispatch(conn: &mut Connection, args: &[String]) -> i32 {
+    let subcommand = args.first().map(String::as_str).unwrap_or_default();
+    let writes = [
+        "save", "tag", "edit", "delete", "clear", "prune", "touch", "import", "merge", "pin",
+        "unpin",
+    ];
+    if writes.contains(&subcommand) && refuse_write(subcommand) {
+        return 1;
+    }
+    match subcommand {
         "list" => {
-            let query = if args.len() > 1 {
-                Some(args[1..].join(" "))
-            } else {
-                None
+            let mut opts = match parse_list_opts(&args[1..], DEFAULT_LIMIT) {
+                Ok(opts) => opts,
+                Err(err) => {
+                    eprintln!("{err}");
+                    return EXIT_USAGE;
+                }
             };
-            let rows = list_cmds(&conn, DEFAULT_LIMIT, query.as_deref()).unwrap_or_default();
-            if rows.is_empty() {
-                println!("no entries");
+            if opts.json_schema {
+                outln!("{MEMO_JSON_SCHEMA}");
                 return 0;
             }
-            for (idx, cmd) in rows {
-                println!("[{idx}] {cmd}");
+            if let Some(code) = recall_query(conn, &mut opts) {
+                return code;
             }
-            return 0;
+            if opts.files {
+                let hits = search_history_files(&opts);
+                let result = open_output(&opts).and_then(|mut out| {
+                    if hits.is_empty() {
+                        writeln!(out, "no entries")?;
+                    }
+                    for (path, cmd) in hits {
+                        writeln!(out, "{}: {cmd}", path.display())?;
+                    }
+                    out.flush()
+                });
+                return write_status(result);
+            }
+            return print_listing(conn, &opts);
         }
-        "save" => {
-            if args.len() > 1 {
-                let cmd = args[1..].join(" ");
-                if insert_cmd(&conn, &cmd).is_ok() {
-                    println!("saved");
+        "save" => return save_command(conn, &args[1..]),
+        "print" => {
+            let mut from_oldest = false;
+            let mut numbered = false;
+            let mut json = json_output();
+            let mut positional = Vec::new();
+            for arg in &args[1..] {
+                match arg.as_str() {
+                    "--from-oldest" => from_oldest = true,
+                    "--numbered-lines" => numbered = true,
+                    "--json" => json = true,
+                    _ => positional.push(arg),
                 }
-                return 0;
             }
-            let last_cmd = read_last_history_command();
-            if last_cmd.is_none() {
-                println!("no history command found");
-                return 0;
-            }
-            if let Some(cmd) = last_cmd {
-                let last_saved = last_saved_cmd(&conn).ok().flatten();
-                if last_saved.as_deref() != Some(&cmd) {
-                    let _ = insert_cmd(&conn, &cmd);
+            let [arg] = positional[..] else {
+                usage();
+                return EXIT_USAGE;
+            };
+            match lookup_memo(conn, arg, from_oldest) {
+                Ok(memo) if json => outln!("{}", memo_json(&memo)),
+                Ok(memo) if numbered => {
+                    let width = memo.cmd.lines().count().to_string().len();
+                    for (n, line) in memo.cmd.lines().enumerate() {
+                        outln!("{:>width$}  {line}", n + 1);
+                    }
                 }
+                Ok(memo) => outln!("{}", memo.cmd),
+                Err(code) => return code,
             }
-            println!("saved");
             return 0;
         }
-        "print" => {
-            if args.len() != 2 || args[1].parse::<usize>().is_err() {
+        "run" => return run_command(conn, &args[1..]),
+        "pick" => return pick_command(conn, &args[1..]),
+        "stats" => return stats_command(conn, &args[1..]),
+        "top" => return top_command(conn, &args[1..]),
+        "doctor" => return doctor_command(conn, &args[1..]),
+        "tag" => return tag_command(conn, &args[1..]),
+        "edit" => return edit_command(conn, &args[1..]),
+        "seq" => return seq_command(conn, &args[1..]),
+        "run-seq" => return run_seq_command(conn, &args[1..]),
+        "prune" => {
+            let flag_ok = |f: &String| f == "--duplicates-only" || f == "--adjacent";
+            if !matches!(&args[1..], [f] if flag_ok(f)) {
                 usage();
-                return 2;
+                return EXIT_USAGE;
             }
-            let idx = args[1].parse::<usize>().unwrap_or(0);
-            match cmd_by_index(&conn, idx).ok().flatten() {
-                Some(cmd) => {
-                    println!("{cmd}");
+            match prune_adjacent(conn) {
+                Ok(removed) => {
+                    outln!("pruned {removed} adjacent duplicates");
                     return 0;
                 }
-                None => {
-                    eprintln!("not found");
+                Err(err) => {
+                    eprintln!("db error: {err}");
                     return 1;
                 }
             }
         }
-        "run" => {
-            if args.len() != 2 || args[1].parse::<usize>().is_err() {
+        "delete" => {
+            let indexes: Vec<&str> = args[1..]
+                .iter()
+                .flat_map(|arg| arg.split(','))
+                .map(str::trim)
+                .filter(|arg| !arg.is_empty())
+                .collect();
+            if indexes.is_empty() {
                 usage();
-                return 2;
+                return EXIT_USAGE;
+            }
+            match delete_indexes(conn, &indexes) {
+                Ok(()) => {
+                    for idx in indexes {
+                        outln!("deleted [{idx}]");
+                    }
+                    return 0;
+                }
+                Err(Some(arg)) => {
+                    eprintln!("not found: {arg}");
+                    return EXIT_NOT_FOUND;
+                }
+                Err(None) => return 1,
+            }
+        }
+        "clear" => {
+            let force = match &args[1..] {
+                [] => false,
+                [flag] if flag == "--force" || flag == "-f" => true,
+                _ => {
+                    usage();
+                    return EXIT_USAGE;
+                }
+            };
+            let cleared = conn
+                .query_row("SELECT COUNT(*) FROM memos", [], |row| row.get::<_, i64>(0))
+                .and_then(|count| {
+                    if count > 0 && !force && !confirm(&format!("clear all {count} entries?")) {
+                        return Ok(None);
+                    }
+                    let _signals = DeferSignals::new();
+                    conn.execute("DELETE FROM memos", []).map(Some)
+                });
+            match cleared {
+                Ok(Some(count)) => outln!("cleared {count} entries"),
+                Ok(None) => return 1,
+                Err(err) => {
+                    eprintln!("db error: {err}");
+                    return 1;
+                }
             }
-            let idx = args[1].parse::<usize>().unwrap_or(0);
-            let cmd = match cmd_by_index(&conn, idx).ok().flatten() {
-                Some(cmd) => cmd,
+            return 0;
+        }
+        "touch" => {
+            let idx = match args.get(1).map(|a| a.parse::<usize>()) {
+                Some(Ok(idx)) if args.len() == 2 => idx,
+                _ => {
+                    usage();
+                    return EXIT_USAGE;
+                }
+            };
+            let id = match id_by_index(conn, idx, false).ok().flatten() {
+                Some(id) => id,
                 None => {
                     eprintln!("not found");
                     return 1;
                 }
             };
-            if is_dangerous(&cmd) && !confirm_run() {
+            let _signals = DeferSignals::new();
+            if let Err(err) = touch_row(conn, id, now_secs()) {
+                eprintln!("db error: {err}");
                 return 1;
             }
-            let status = Command::new("sh").arg("-c").arg(&cmd).status();
-            return status.ok().and_then(|s| s.code()).unwrap_or(1);
+            outln!("touched [{idx}], now [1]");
+            return 0;
         }
-        "_list" => {
-            let rows = list_cmds(&conn, DB_CAP, None).unwrap_or_default();
-            for (idx, cmd) in rows {
-                println!("{idx}\t{cmd}");
+        "pin" | "unpin" => {
+            let [_, arg] = args else {
+                usage();
+                return EXIT_USAGE;
+            };
+            let memo = match lookup_memo(conn, arg, false) {
+                Ok(memo) => memo,
+                Err(code) => return code,
+            };
+            let pinned = args[0] == "pin";
+            let _signals = DeferSignals::new();
+            let updated =
+                conn.execute("UPDATE memos SET pinned = ? WHERE id = ?", params![pinned, memo.id]);
+            if let Err(err) = updated {
+                eprintln!("db error: {err}");
+                return 1;
+            }
+            let verb = if pinned { "pinned" } else { "unpinned" };
+            outln!("{verb} [{}]", memo.index);
+            return 0;
+        }
+        "resolve" => {
+            if args.len() < 2 {
+                usage();
+                return EXIT_USAGE;
+            }
+            match resolve_ref(conn, &args[1..].join(" ")) {
+                Ok(cmd) => {
+                    outln!("{cmd}");
+                    return 0;
+                }
+                Err(code) => {
+                    if code == EXIT_UNRESOLVED {
+                        eprintln!("not found");
+                    }
+                    return code;
+                }
+            }
+        }
+        "import" => return import_command(conn, &args[1..]),
+        "merge" => {
+            if args.len() != 2 {
+                usage();
+                return EXIT_USAGE;
+            }
+            let path = expand_home(&args[1]);
+            if !path.is_file() {
+                eprintln!("not found: {}", path.display());
+                return 1;
+            }
+            match merge_db(conn, &path) {
+                Ok((added, skipped)) => {
+                    outln!("merged: {added} added, {skipped} duplicates skipped");
+                    return 0;
+                }
+                Err(err) => {
+                    eprintln!("db error: {err}");
+                    return 1;
+                }
+            }
+        }
+        "completion" => {
+            let script = match &args[1..] {
+                [shell] if shell == "bash" => include_str!("../scripts/completion.bash"),
+                [shell] if shell == "zsh" => include_str!("../scripts/completion.zsh"),
+                [shell] if shell == "fish" => include_str!("../scripts/completion.fish"),
+                _ => {
+                    usage();
+                    return EXIT_USAGE;
+                }
+            };
+            outln!("{}", script.trim_end());
+            return 0;
+        }
+        "_complete" => {
+            let prefix = args[1..].join(" ");
+            let mut opts = ListOpts::new(usize::MAX, None);
+            opts.distinct = true;
+            for memo in list_cmds(conn, &opts).unwrap_or_default() {
+                // Completions are read one per line.
+                if memo.cmd.starts_with(&prefix) && !memo.cmd.contains('\n') {
+                    outln!("{}", memo.cmd);
+                }
             }
             return 0;
         }
+        "_list" => {
+            // Consumers parse `index<TAB>command`; the hash column is opt-in.
+            let with_hash = args[1..].iter().any(|a| a == "--with-hash");
+            let rest: Vec<String> =
+                args[1..].iter().filter(|a| *a != "--with-hash").cloned().collect();
+            let opts = match parse_list_opts(&rest, usize::MAX) {
+                Ok(opts) => opts,
+                Err(err) => {
+                    eprintln!("{err}");
+                    return EXIT_USAGE;
+                }
+            };
+            let _ = each_cmd(conn, &opts, |memo| {
+                if with_hash {
+                    outln!("{}\t{}\t{}", memo.index, short_hash(&memo.cmd), memo.cmd);
+                } else {
+                    outln!("{}\t{}", memo.index, memo.cmd);
+                }
+                true
+            });
+            return 0;
+        }
         _ => {}
     }
 
-    if args.len() == 1 && args[0].parse::<usize>().is_ok() {
-        let idx = args[0].parse::<usize>().unwrap_or(0);
-        match cmd_by_index(&conn, idx).ok().flatten() {
+    let copy_idx = match args {
+        [n] => {
+            let set_title = env::var("MEMO_SET_TITLE").is_ok_and(|v| v == "1");
+            n.parse::<usize>().ok().map(|idx| (idx, set_title))
+        }
+        [flag, n] | [n, flag] if flag == "--set-title" => {
+            n.parse::<usize>().ok().map(|idx| (idx, true))
+        }
+        _ => None,
+    };
+    if let Some((idx, set_title)) = copy_idx {
+        match cmd_by_index(conn, idx, false).ok().flatten() {
             Some(cmd) => {
+                record_use(conn, &cmd);
                 if copy_to_clipboard(&cmd) {
-                    println!("copied [{idx}]");
-                } else {
-                    println!("{cmd}");
-                    eprintln!("warning: clipboard unavailable");
+                    if set_title {
+                        set_terminal_title(&format!("memo: {cmd}"));
+                    }
+                    eprintln!("copied [{idx}]");
+                    return 0;
                 }
-                return 0;
+                outln!("{cmd}");
+                eprintln!("warning: clipboard unavailable");
+                return EXIT_COPY_FALLBACK;
             }
             None => {
                 eprintln!("not found");
@@ -386,14 +4766,240 @@ fn main() -> i32 {
         }
     }
 
-    let query = args.join(" ");
-    let rows = list_cmds(&conn, DEFAULT_LIMIT, Some(&query)).unwrap_or_default();
-    if rows.is_empty() {
-        println!("no entries");
-        return 0;
+    let mut opts = match parse_list_opts(args, DEFAULT_LIMIT) {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("{err}");
+            return EXIT_USAGE;
+        }
+    };
+    if let Some(code) = recall_query(conn, &mut opts) {
+        return code;
     }
-    for (idx, cmd) in rows {
-        println!("[{idx}] {cmd}");
+    if opts.query.is_none() && !read_only() && auto_save_allowed(conn) {
+        if let Some(entry) = read_last_history_command(None) {
+            let mut save = SaveOpts::new("history");
+            save.created_at = entry.timestamp;
+            let cmd = if save.trim_amp { trim_trailing_amp(&entry.cmd) } else { &entry.cmd };
+            let last_saved = last_saved_cmd(conn).ok().flatten();
+            if !cmd.is_empty() && last_saved.as_deref() != Some(cmd) {
+                let _ = insert_cmd(conn, cmd, &save);
+            }
+        }
+    }
+    print_listing(conn, &opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_db() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        register_plain_cmd(&conn).unwrap();
+        conn
+    }
+
+    /// Inserts `n` rows, oldest first, as `echo 0`, `echo 1`, ...
+    fn insert_rows(conn: &Connection, n: usize) {
+        for i in 0..n {
+            conn.execute(
+                "INSERT INTO memos (cmd, created_at) VALUES (?, ?)",
+                params![format!("echo {i}"), i as i64],
+            )
+            .unwrap();
+        }
+    }
+
+    fn cmds(conn: &Connection) -> Vec<String> {
+        let opts = ListOpts::new(usize::MAX, None);
+        list_cmds(conn, &opts).unwrap().into_iter().map(|m| m.cmd).collect()
+    }
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    fn ids_since(conn: &Connection, words: &[&str]) -> Vec<i64> {
+        let opts = parse_list_opts(&args(words), DEFAULT_LIMIT).unwrap();
+        list_cmds(conn, &opts).unwrap().into_iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn time_style_forms() {
+        let style = |words: &[&str]| parse_list_opts(&args(words), DEFAULT_LIMIT).map(|o| o.time);
+        assert!(matches!(style(&["--time"]), Ok(Some(TimeStyle::Relative))));
+        for words in [&["--time=iso"][..], &["--time", "iso"], &["--time iso"]] {
+            assert!(matches!(style(words), Ok(Some(TimeStyle::Iso))), "{words:?}");
+        }
+        let opts = parse_list_opts(&args(&["--time", "git"]), DEFAULT_LIMIT).unwrap();
+        assert_eq!(opts.query.as_deref(), Some("git"));
+        let err = parse_list_opts(&args(&["--time=utc"]), DEFAULT_LIMIT).err().unwrap();
+        assert!(err.contains("--time=iso"), "{err}");
+    }
+
+    #[test]
+    fn since_id_walks_ids_in_order() {
+        let conn = memory_db();
+        // Newer ids with older times, as merge and import leave them.
+        for i in 0..15 {
+            conn.execute(
+                "INSERT INTO memos (cmd, created_at) VALUES (?, ?)",
+                params![format!("echo {i}"), 100 - i],
+            )
+            .unwrap();
+        }
+        assert_eq!(ids_since(&conn, &["--since-id", "0", "--limit", "1"]), [1]);
+        assert_eq!(ids_since(&conn, &["--since-id", "1", "--limit", "2"]), [2, 3]);
+        // No default limit: the rest come back in one call.
+        assert_eq!(ids_since(&conn, &["--since-id", "3"]), (4..=15).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn touch_row_never_reuses_a_deleted_id() {
+        let conn = memory_db();
+        insert_rows(&conn, 3);
+        conn.execute("DELETE FROM memos WHERE id = 3", []).unwrap();
+        assert_eq!(touch_row(&conn, 1, 50).unwrap(), 4);
+    }
+
+    #[test]
+    fn dedup_folds_every_copy_into_one() {
+        let conn = memory_db();
+        for (cmd, tags, uses) in [("ls", "a", 2), ("cd foo", "", 0), ("ls", "b", 3)] {
+            conn.execute(
+                "INSERT INTO memos (cmd, created_at, tags, uses) VALUES (?, 1, ?, ?)",
+                params![cmd, tags, uses],
+            )
+            .unwrap();
+        }
+        conn.execute("UPDATE memos SET pinned = 1 WHERE id = 1", []).unwrap();
+        let mut save = SaveOpts::new("manual");
+        save.dedup = true;
+        save.created_at = Some(10);
+        assert!(insert_cmd(&conn, "ls", &save).unwrap());
+        assert_eq!(cmds(&conn), ["ls", "cd foo"]);
+        let (tags, pinned, uses): (String, bool, i64) = conn
+            .query_row("SELECT tags, pinned, uses FROM memos WHERE cmd = 'ls'", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!((tags.as_str(), pinned, uses), ("b,a", true, 5));
+        // A new command is simply added.
+        assert!(insert_cmd(&conn, "pwd", &save).unwrap());
+        assert_eq!(cmds(&conn), ["pwd", "ls", "cd foo"]);
+    }
+
+    #[test]
+    fn lookup_by_hash_prefix() {
+        let conn = memory_db();
+        insert_rows(&conn, 3);
+        let full = cmd_hash("echo 1");
+        for len in [MIN_HASH_PREFIX, SHORT_HASH_LEN, full.len()] {
+            assert_eq!(lookup_memo(&conn, &full[..len], false).unwrap().cmd, "echo 1");
+        }
+        assert_eq!(lookup_memo(&conn, &full.to_uppercase(), false).unwrap().cmd, "echo 1");
+        // Hints for a long ambiguous prefix stop at the end of the hash.
+        assert_eq!(longer_hash("echo 1", 15), full);
+        assert_eq!(longer_hash("echo 1", 16), full);
+        assert_eq!(longer_hash("echo 1", 4), full[..6]);
+    }
+
+    #[test]
+    fn upgrades_unversioned_db() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE memos (\
+             id INTEGER PRIMARY KEY AUTOINCREMENT, \
+             cmd TEXT NOT NULL, \
+             created_at INTEGER NOT NULL);
+             INSERT INTO memos (cmd, created_at) VALUES ('ls', 1), ('pwd', 2);",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        register_plain_cmd(&conn).unwrap();
+        let version: usize =
+            conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        assert_eq!(cmds(&conn), ["pwd", "ls"]);
+        // A second run has nothing left to do.
+        migrate(&mut conn).unwrap();
+        assert_eq!(cmds(&conn), ["pwd", "ls"]);
+    }
+
+    #[test]
+    fn configured_cap_evicts_oldest_rows() {
+        let conn = memory_db();
+        let cap = parse_cap("20");
+        assert_eq!(cap, Some(20));
+        insert_rows(&conn, 25);
+        enforce_cap(&conn, cap).unwrap();
+        let left = cmds(&conn);
+        assert_eq!(left.len(), 20);
+        assert_eq!(left.last().unwrap(), "echo 5");
+    }
+
+    #[test]
+    fn unparseable_cap_falls_back_to_default() {
+        assert_eq!(parse_cap("lots"), Some(DB_CAP));
+        assert_eq!(parse_cap(""), Some(DB_CAP));
+        let conn = memory_db();
+        insert_rows(&conn, DB_CAP + 5);
+        enforce_cap(&conn, parse_cap("lots")).unwrap();
+        assert_eq!(cmds(&conn).len(), DB_CAP);
+    }
+
+    #[test]
+    fn zero_cap_keeps_every_row() {
+        let conn = memory_db();
+        insert_rows(&conn, 3);
+        let cap = parse_cap("0");
+        assert_eq!(cap, None);
+        enforce_cap(&conn, cap).unwrap();
+        assert_eq!(cmds(&conn).len(), 3);
+    }
+
+    #[test]
+    fn negative_cap_never_empties_the_table() {
+        let conn = memory_db();
+        insert_rows(&conn, 3);
+        let cap = parse_cap("-5");
+        assert_eq!(cap, Some(DB_CAP));
+        enforce_cap(&conn, cap).unwrap();
+        assert_eq!(cmds(&conn).len(), 3);
+    }
+
+    #[test]
+    fn wrapped_memo_invocations_are_recognized() {
+        for cmd in [
+            "memo list",
+            "  /usr/local/bin/memo",
+            "sudo -u root memo list",
+            "sudo -E --user root memo",
+            "FOO=1 env -u HOME memo",
+            "nice -n 10 memo",
+            "timeout 5 memo list",
+            "timeout -s KILL -k 2 5s memo",
+            "exec -a m memo",
+        ] {
+            assert!(is_memo_invocation(cmd), "{cmd}");
+        }
+        for cmd in ["sudo -u memo ls", "timeout 5", "nice -n 5 vim memo", "memorize"] {
+            assert!(!is_memo_invocation(cmd), "{cmd}");
+        }
+    }
+
+    #[test]
+    fn expand_home_paths() {
+        let home = Path::new("/home/me");
+        let expand = |path| expand_home_with(path, home);
+        assert_eq!(expand("~"), PathBuf::from("/home/me"));
+        assert_eq!(expand("~/"), PathBuf::from("/home/me"));
+        assert_eq!(expand("~//x"), PathBuf::from("/home/me/x"));
+        assert_eq!(expand("~/.local/state"), PathBuf::from("/home/me/.local/state"));
+        assert_eq!(expand("/var/lib/memo"), PathBuf::from("/var/lib/memo"));
+        assert_eq!(expand("~user/x"), PathBuf::from("~user/x"));
+        assert_eq!(expand("a/~/b"), PathBuf::from("a/~/b"));
     }
-    0
 }